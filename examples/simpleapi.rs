@@ -51,9 +51,9 @@ fn main() {
      * top of the stack.
      */
     for i in 1..6 {
-        L.pushinteger(i);   // Push the table index
-        L.pushinteger(i*2); // Push the cell value
-        L.rawset(-3);       // Stores the pair in the table
+        L.push(i);     // Push the table index
+        L.push(i*2);   // Push the cell value
+        L.rawset(-3);  // Stores the pair in the table
     }
 
     // By what name is the script going to reference our table?
@@ -70,7 +70,7 @@ fn main() {
     }
 
     // Get the returned value at the to of the stack (index -1)
-    let sum = L.tonumber(-1);
+    let sum: f64 = L.read(-1).unwrap_or(0.0);
 
     println!("Script returned: {}", sum);
 
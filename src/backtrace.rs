@@ -0,0 +1,157 @@
+//! Structured stack traces
+
+use libc;
+use std::ffi::CStr;
+use std::fmt;
+
+use {State, ExternState, RawState, Debug};
+
+/// An activation record of the Lua stack, with owned copies of the fields
+/// filled in by getinfo("Snl").
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Frame {
+    /// The source of the chunk that defined the function: "@" followed by a
+    /// file name, "=" followed by a description, or the source code
+    pub source: String,
+    /// A printable version of `source`, for error messages
+    pub short_src: String,
+    /// The line being executed, if known
+    pub currentline: Option<i32>,
+    /// The line where the definition of the function starts
+    pub linedefined: i32,
+    /// A reasonable name for the function, if one could be found
+    pub name: Option<String>,
+    /// Explains `name`: "global", "local", "method", "field", "upvalue", or ""
+    pub namewhat: String,
+    /// "Lua", "C", "main", or "tail"
+    pub what: String
+}
+
+impl Frame {
+    /// Returns the frame described by `ar`, usually filled in by getinfo()
+    /// with "Snl". Fields that were not filled in are empty. The string
+    /// pointers of `ar` must be null or valid, as left by Debug::new() and
+    /// getinfo(), and `short_src` must be NUL-terminated.
+    pub unsafe fn from_debug(ar: &Debug) -> Frame {
+        Frame{
+            source: cstr_or_empty(ar.source),
+            short_src: CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy().into_owned(),
+            currentline: if ar.currentline > 0 { Some(ar.currentline) } else { None },
+            linedefined: ar.linedefined,
+            name: if ar.name.is_null() { None } else { Some(cstr_or_empty(ar.name)) },
+            namewhat: cstr_or_empty(ar.namewhat),
+            what: cstr_or_empty(ar.what)
+        }
+    }
+}
+
+/// Formats the frame like a line of debug.traceback(), without the leading
+/// tab.
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.short_src)?;
+        if let Some(line) = self.currentline {
+            write!(f, "{}:", line)?;
+        }
+        if !self.namewhat.is_empty() {
+            write!(f, " in function '{}'", self.name.as_ref().map_or("", |s| &s[..]))
+        } else if self.what == "main" {
+            f.write_str(" in main chunk")
+        } else if self.what == "C" || self.what == "tail" {
+            f.write_str(" ?")
+        } else {
+            write!(f, " in function <{}:{}>", self.short_src, self.linedefined)
+        }
+    }
+}
+
+// Number of frames shown at the top and bottom of a long traceback
+const LEVELS1: usize = 11;
+const LEVELS2: usize = 10;
+
+/// Formats a list of frames like debug.traceback(), including the "stack
+/// traceback:" header. The middle of a long stack is elided like Lua does.
+#[derive(Copy,Clone,Debug)]
+pub struct Traceback<'a>(pub &'a [Frame]);
+
+impl<'a> fmt::Display for Traceback<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("stack traceback:")?;
+        let frames = self.0;
+        if frames.len() > LEVELS1 + 1 + LEVELS2 {
+            for frame in &frames[..LEVELS1] {
+                write!(f, "\n\t{}", frame)?;
+            }
+            f.write_str("\n\t...")?;
+            for frame in &frames[frames.len() - LEVELS2..] {
+                write!(f, "\n\t{}", frame)?;
+            }
+        } else {
+            for frame in frames {
+                write!(f, "\n\t{}", frame)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    /// Returns the frames of the Lua stack, starting at level 0 (the running
+    /// function). Format them with Traceback for debug.traceback() output.
+    pub fn backtrace(&mut self) -> Vec<Frame> {
+        #![inline(always)]
+        unsafe { self.as_extern().backtrace() }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn backtrace(&mut self) -> Vec<Frame> {
+        self.as_raw().backtrace()
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn backtrace(&mut self) -> Vec<Frame> {
+        frames(self, 0)
+    }
+}
+
+/// Returns the frames of the stack of `L`, starting at `level`.
+pub unsafe fn frames(L: &mut RawState, mut level: i32) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    while let Some(mut ar) = L.getstack(level) {
+        L.getinfo("Snl", &mut ar);
+        frames.push(Frame::from_debug(&ar));
+        level += 1;
+    }
+    frames
+}
+
+/// Returns the name of the file of `frame`: the path of a chunk loaded from
+/// a file, or the short source of any other chunk.
+pub fn file_name(frame: &Frame) -> String {
+    if frame.source.starts_with('@') {
+        frame.source[1..].to_string()
+    } else {
+        frame.short_src.clone()
+    }
+}
+
+/// Like file_name(), for an activation record filled in by getinfo() with at
+/// least "S". The other fields of `ar` are not read.
+pub unsafe fn debug_file_name(ar: &Debug) -> String {
+    match cstr_or_empty(ar.source).strip_prefix('@') {
+        Some(file) => file.to_string(),
+        None => CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy().into_owned()
+    }
+}
+
+unsafe fn cstr_or_empty(s: *const libc::c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(s).to_string_lossy().into_owned()
+    }
+}
@@ -0,0 +1,232 @@
+//! Rust closures as Lua functions
+
+use libc;
+use libc::c_int;
+use std::{mem, ptr};
+use std::panic::{self, AssertUnwindSafe};
+
+use {State, ExternState, RawState, Frame, Type, REGISTRYINDEX, MULTRET};
+use raw;
+use macros::{extern_call, push_panic_message};
+
+/// The boxed form of a closure as stored in its userdata.
+type BoxedClosure = Box<dyn FnMut(&mut ExternState) -> i32>;
+
+/// A closure userdata: the closure, and whether it is running
+struct ClosureCell {
+    running: bool,
+    f: BoxedClosure
+}
+
+// The address of this static is the registry key for the closure metatable
+static CLOSURE_METATABLE: u8 = 0;
+
+impl State {
+    /// Pushes a Rust closure onto the stack as a Lua function.
+    ///
+    /// The closure is called like a CFunction: it receives the arguments on
+    /// the stack of the given ExternState and returns the number of results it
+    /// pushed. It is moved into a Lua userdata and is dropped when the
+    /// function is garbage-collected (or when the state is closed).
+    ///
+    /// Calling the function again while the closure is running, e.g. from
+    /// Lua code the closure calls, raises a Lua error instead of re-entering
+    /// the closure. The closure runs in protected mode so it can be marked
+    /// as done even if it raises an error, which means it cannot yield. The
+    /// errors it raises still get the position and name of its caller, like
+    /// those of a CFunction. A panic in the closure is raised as a Lua error.
+    pub fn push_closure<F>(&mut self, f: F) where F: FnMut(&mut ExternState) -> i32 + 'static {
+        #![inline(always)]
+        unsafe { self.as_extern().push_closure(f) }
+    }
+
+    /// Sets the Rust closure `f` as the new value of global `name`.
+    /// See push_closure() for details.
+    ///
+    /// Fails the task if `name` has interior NULs.
+    pub fn register_closure<F>(&mut self, name: &str, f: F)
+                              where F: FnMut(&mut ExternState) -> i32 + 'static {
+        #![inline(always)]
+        unsafe { self.as_extern().register_closure(name, f) }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn push_closure<F>(&mut self, f: F) where F: FnMut(&mut ExternState) -> i32 + 'static {
+        // the userdata and a copy, its metatable, and up to 2 slots for
+        // creating the metatable
+        self.checkstack_(5);
+        self.as_raw().push_closure(f)
+    }
+
+    pub unsafe fn register_closure<F>(&mut self, name: &str, f: F)
+                                     where F: FnMut(&mut ExternState) -> i32 + 'static {
+        self.checkstack_(5);
+        self.as_raw().register_closure(name, f)
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn push_closure<F>(&mut self, f: F) where F: FnMut(&mut ExternState) -> i32 + 'static {
+        let cell = ClosureCell{ running: false, f: Box::new(f) };
+        let p = self.newuserdata(mem::size_of::<ClosureCell>()) as *mut ClosureCell;
+        ptr::write(p, cell);
+        self.push_closure_metatable();
+        self.setmetatable(-2);
+        self.pushvalue(-1);
+        self.pushcclosure(closure_call, 1);
+        self.push_error_handler();
+        self.pushcclosure(closure_trampoline, 3);
+    }
+
+    pub unsafe fn register_closure<F>(&mut self, name: &str, f: F)
+                                     where F: FnMut(&mut ExternState) -> i32 + 'static {
+        self.push_closure(f);
+        self.setglobal(name)
+    }
+
+    /// Pushes the metatable shared by all closure userdata, creating it on
+    /// first use.
+    unsafe fn push_closure_metatable(&mut self) {
+        let key = &CLOSURE_METATABLE as *const u8 as *mut libc::c_void;
+        self.pushlightuserdata(key);
+        self.rawget(REGISTRYINDEX);
+        if self.isnil(-1) {
+            self.pop(1);
+            self.createtable(0, 2);
+            self.pushcfunction(closure_gc);
+            self.setfield(-2, "__gc");
+            // hide the metatable from scripts
+            self.pushboolean(false);
+            self.setfield(-2, "__metatable");
+            self.pushlightuserdata(key);
+            self.pushvalue(-2);
+            self.rawset(REGISTRYINDEX);
+        }
+    }
+
+    /// Pushes the message handler pcall_upvalue() needs to call the function
+    /// on top of the stack.
+    pub unsafe fn push_error_handler(&mut self) {
+        self.pushvalue(-1);
+        self.pushcclosure(error_handler, 1);
+    }
+}
+
+/// Calls the closure in upvalue 2 in protected mode, with the message handler
+/// in upvalue 3, unless the closure in upvalue 1 is already running.
+unsafe extern "C" fn closure_trampoline(L: *mut raw::lua_State) -> c_int {
+    let cell = raw::lua_touserdata(L, raw::lua_upvalueindex(1)) as *mut ClosureCell;
+    if (*cell).running {
+        RawState::from_lua_State(L).errorstr("cannot call a Rust closure that is already running")
+    }
+    (*cell).running = true;
+    let status = pcall_upvalue(L, 2, raw::lua_gettop(L));
+    (*cell).running = false;
+    if status != 0 {
+        raw::lua_error(L)
+    } else {
+        raw::lua_gettop(L)
+    }
+}
+
+unsafe extern "C" fn closure_call(L: *mut raw::lua_State) -> c_int {
+    let cell = raw::lua_touserdata(L, raw::lua_upvalueindex(1)) as *mut ClosureCell;
+    extern_call(L, || {
+        let mut state = ExternState::from_lua_State(L);
+        ((*cell).f)(&mut state)
+    })
+}
+
+unsafe extern "C" fn closure_gc(L: *mut raw::lua_State) -> c_int {
+    let cell = raw::lua_touserdata(L, 1) as *mut ClosureCell;
+    drop_in_lua(L, cell)
+}
+
+/// Calls the function in upvalue `upvalue` of the running CFunction in
+/// protected mode, with the `nargs` values on top of the stack as arguments
+/// and the handler made by push_error_handler() in upvalue `upvalue + 1`.
+/// Returns the status of lua_pcall(), leaving the results or the error
+/// object on the stack.
+///
+/// This lets a CFunction clean up after code that may raise an error.
+pub unsafe fn pcall_upvalue(L: *mut raw::lua_State, upvalue: c_int, nargs: c_int) -> c_int {
+    RawState::from_lua_State(L).checkstack_(2);
+    let base = raw::lua_gettop(L) - nargs;
+    raw::lua_pushvalue(L, raw::lua_upvalueindex(upvalue + 1));
+    raw::lua_insert(L, base + 1);
+    raw::lua_pushvalue(L, raw::lua_upvalueindex(upvalue));
+    raw::lua_insert(L, base + 2);
+    let status = raw::lua_pcall(L, nargs, MULTRET, base + 1);
+    raw::lua_remove(L, base + 1);
+    status
+}
+
+/// The message handler of pcall_upvalue(). An error raised by the function
+/// in upvalue 1 itself was made as if the CFunction calling it, at level 2,
+/// was its caller. This gives it the position and name it would have had if
+/// the function had been called directly, like luaL_error() and
+/// luaL_argerror() do. Other errors are left unchanged.
+unsafe extern "C" fn error_handler(L: *mut raw::lua_State) -> c_int {
+    let mut L = RawState::from_lua_State(L);
+    if L.type_(1) != Some(Type::String) {
+        return 1;
+    }
+    let direct = match L.getstack(1) {
+        Some(mut ar) => {
+            L.getinfo("f", &mut ar);
+            let same = L.rawequal(-1, raw::lua_upvalueindex(1));
+            L.pop(1);
+            same
+        }
+        None => false
+    };
+    let frame = match L.getstack(2) {
+        Some(mut ar) if direct => {
+            L.getinfo("n", &mut ar);
+            Frame::from_debug(&ar)
+        }
+        _ => return 1
+    };
+    let msg = name_argerror(&L.describe(1), &frame);
+    L.where_(3);
+    L.pushstring(&msg);
+    L.concat(2);
+    1
+}
+
+/// Rewrites a message of luaL_argerror() that could not name the function,
+/// the way it would have been written for the function called as `frame`.
+fn name_argerror(msg: &str, frame: &Frame) -> String {
+    const UNNAMED: &str = " to '?' (";
+    let parsed = msg.strip_prefix("bad argument #").and_then(|rest| {
+        let at = rest.find(UNNAMED)?;
+        Some((rest[..at].parse::<i32>().ok()?, &rest[at + UNNAMED.len()..]))
+    });
+    let (mut narg, extra) = match parsed {
+        Some(parsed) => parsed,
+        None => return msg.to_string()
+    };
+    let name = frame.name.as_ref().map_or("?", |s| &s[..]);
+    if frame.namewhat == "method" {
+        narg -= 1;
+        if narg == 0 {
+            return format!("calling '{}' on bad self ({}", name, extra);
+        }
+    }
+    format!("bad argument #{} to '{}' ({}", narg, name, extra)
+}
+
+/// Drops the value at `p` in place from a __gc metamethod, raising a panic
+/// in its destructor as a Lua error.
+pub unsafe fn drop_in_lua<T>(L: *mut raw::lua_State, p: *mut T) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(|| ptr::drop_in_place(p))) {
+        Ok(()) => 0,
+        Err(payload) => {
+            push_panic_message(L, payload);
+            raw::lua_error(L)
+        }
+    }
+}
@@ -0,0 +1,393 @@
+//! Conversions between Rust values and Lua values
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::{error, fmt};
+
+use {State, ExternState, RawState, Type};
+
+/// Rust types that can be pushed onto the Lua stack.
+///
+/// Most types push exactly one Lua value. Tuples push one value per element
+/// and `()` pushes nothing, which makes them convenient as the return value
+/// of a C function.
+pub trait ToLua {
+    /// Pushes the value onto the stack of `L` and returns the number of Lua
+    /// values pushed.
+    ///
+    /// The caller guarantees one free stack slot. Implementations that push
+    /// more than one value, or that need temporary slots, must grow the stack
+    /// themselves.
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32;
+}
+
+/// Rust types that can be read from the Lua stack.
+pub trait FromLua: Sized {
+    /// Reads the value at the given acceptable index of `L`. The stack is
+    /// left unchanged and no value on it is modified.
+    ///
+    /// Tuples read one element per stack slot, starting at `idx`.
+    unsafe fn from_lua(L: &mut RawState, idx: i32) -> Result<Self, FromLuaError>;
+}
+
+/// Error returned when a Lua value cannot be converted to the requested Rust
+/// type.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct FromLuaError {
+    /// Description of the expected value
+    pub expected: &'static str,
+    /// Description of the value that was actually found
+    pub got: &'static str
+}
+
+impl FromLuaError {
+    unsafe fn mismatch(L: &mut RawState, idx: i32, expected: &'static str) -> FromLuaError {
+        FromLuaError{ expected: expected, got: L.typename(idx) }
+    }
+}
+
+impl fmt::Display for FromLuaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} expected, got {}", self.expected, self.got)
+    }
+}
+
+impl error::Error for FromLuaError {}
+
+// Rust value conversion
+impl State {
+    /// Pushes the Rust value `val` onto the stack and returns the number of
+    /// Lua values pushed. See `ToLua` for the supported types.
+    pub fn push<T: ToLua>(&mut self, val: T) -> i32 {
+        #![inline(always)]
+        unsafe { self.as_extern().push(val) }
+    }
+
+    /// Converts the value at the given acceptable index to the Rust type `T`.
+    /// The value on the stack is never modified; in particular, reading a
+    /// number as a String does not convert it in place, so this is safe to
+    /// use on keys during table traversal.
+    pub fn read<T: FromLua>(&mut self, idx: i32) -> Result<T, FromLuaError> {
+        #![inline(always)]
+        unsafe { self.as_extern().read(idx) }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn push<T: ToLua>(&mut self, val: T) -> i32 {
+        self.checkstack_(1);
+        self.as_raw().push(val)
+    }
+
+    pub unsafe fn read<T: FromLua>(&mut self, idx: i32) -> Result<T, FromLuaError> {
+        self.check_acceptable(idx);
+        self.as_raw().read(idx)
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn push<T: ToLua>(&mut self, val: T) -> i32 {
+        #![inline]
+        val.push_lua(self)
+    }
+
+    pub unsafe fn read<T: FromLua>(&mut self, idx: i32) -> Result<T, FromLuaError> {
+        #![inline]
+        T::from_lua(self, idx)
+    }
+}
+
+impl<'a, T: ToLua + ?Sized> ToLua for &'a T {
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+        (**self).push_lua(L)
+    }
+}
+
+impl ToLua for () {
+    unsafe fn push_lua(&self, _L: &mut RawState) -> i32 {
+        0
+    }
+}
+
+impl FromLua for () {
+    unsafe fn from_lua(_L: &mut RawState, _idx: i32) -> Result<(), FromLuaError> {
+        Ok(())
+    }
+}
+
+impl ToLua for bool {
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+        L.pushboolean(*self);
+        1
+    }
+}
+
+impl FromLua for bool {
+    unsafe fn from_lua(L: &mut RawState, idx: i32) -> Result<bool, FromLuaError> {
+        if L.isboolean(idx) {
+            Ok(L.toboolean(idx))
+        } else {
+            Err(FromLuaError::mismatch(L, idx, "boolean"))
+        }
+    }
+}
+
+// u8 is deliberately left out so that Vec<u8> and [u8] can map to Lua strings.
+macro_rules! impl_integer {
+    ($($t:ident)+) => (
+        $(
+            impl ToLua for $t {
+                unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+                    L.pushnumber(*self as f64);
+                    1
+                }
+            }
+
+            impl FromLua for $t {
+                unsafe fn from_lua(L: &mut RawState, idx: i32) -> Result<$t, FromLuaError> {
+                    if !L.isnumber(idx) {
+                        return Err(FromLuaError::mismatch(L, idx, stringify!($t)));
+                    }
+                    let n = L.tonumber(idx);
+                    if n.fract() != 0.0 {
+                        Err(FromLuaError{ expected: stringify!($t), got: "non-integral number" })
+                    } else if n < $t::MIN as f64 || n >= $t::MAX as f64 + 1.0 {
+                        // MAX as f64 may round up to MAX + 1, a power of two;
+                        // adding 1.0 to it then still gives MAX + 1
+                        Err(FromLuaError{ expected: stringify!($t), got: "out-of-range number" })
+                    } else {
+                        Ok(n as $t)
+                    }
+                }
+            }
+        )+
+    )
+}
+
+impl_integer!(i8 i16 i32 i64 isize u16 u32 u64 usize);
+
+macro_rules! impl_float {
+    ($($t:ident)+) => (
+        $(
+            impl ToLua for $t {
+                unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+                    L.pushnumber(*self as f64);
+                    1
+                }
+            }
+
+            impl FromLua for $t {
+                unsafe fn from_lua(L: &mut RawState, idx: i32) -> Result<$t, FromLuaError> {
+                    if L.isnumber(idx) {
+                        Ok(L.tonumber(idx) as $t)
+                    } else {
+                        Err(FromLuaError::mismatch(L, idx, "number"))
+                    }
+                }
+            }
+        )+
+    )
+}
+
+impl_float!(f32 f64);
+
+impl ToLua for str {
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+        L.pushstring(self);
+        1
+    }
+}
+
+impl ToLua for String {
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+        L.pushstring(self);
+        1
+    }
+}
+
+impl FromLua for String {
+    unsafe fn from_lua(L: &mut RawState, idx: i32) -> Result<String, FromLuaError> {
+        let bytes = Vec::<u8>::from_lua(L, idx).map_err(|mut e| {
+            e.expected = "string";
+            e
+        })?;
+        String::from_utf8(bytes).map_err(|_| FromLuaError{ expected: "utf-8 string", got: "string" })
+    }
+}
+
+impl ToLua for [u8] {
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+        L.pushbytes(self);
+        1
+    }
+}
+
+impl ToLua for Vec<u8> {
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+        L.pushbytes(self);
+        1
+    }
+}
+
+impl FromLua for Vec<u8> {
+    unsafe fn from_lua(L: &mut RawState, idx: i32) -> Result<Vec<u8>, FromLuaError> {
+        match L.type_(idx) {
+            Some(Type::String) => Ok(L.tobytes(idx).unwrap_or(&[]).to_vec()),
+            Some(Type::Number) => {
+                // lua_tolstring() converts numbers in place, so convert a copy instead
+                L.checkstack_(1);
+                L.pushvalue(idx);
+                let bytes = L.tobytes(-1).unwrap_or(&[]).to_vec();
+                L.pop(1);
+                Ok(bytes)
+            }
+            _ => Err(FromLuaError::mismatch(L, idx, "bytes"))
+        }
+    }
+}
+
+impl<T: ToLua> ToLua for Option<T> {
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+        match *self {
+            Some(ref val) => val.push_lua(L),
+            None => {
+                L.pushnil();
+                1
+            }
+        }
+    }
+}
+
+impl<T: FromLua> FromLua for Option<T> {
+    unsafe fn from_lua(L: &mut RawState, idx: i32) -> Result<Option<T>, FromLuaError> {
+        if L.isnoneornil(idx) {
+            Ok(None)
+        } else {
+            T::from_lua(L, idx).map(Some)
+        }
+    }
+}
+
+/// Pushes `val` and adjusts the result to exactly one value, the way Lua
+/// adjusts an expression used as a table field.
+pub unsafe fn push_one<T: ToLua + ?Sized>(L: &mut RawState, val: &T) {
+    match val.push_lua(L) {
+        0 => L.pushnil(),
+        1 => (),
+        n => L.pop(n - 1)
+    }
+}
+
+impl<T: ToLua> ToLua for [T] {
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+        L.createtable(self.len() as i32, 0);
+        L.checkstack_(1);
+        for (i, val) in self.iter().enumerate() {
+            push_one(L, val);
+            L.rawseti(-2, i as i32 + 1);
+        }
+        1
+    }
+}
+
+impl<T: ToLua> ToLua for Vec<T> {
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+        self[..].push_lua(L)
+    }
+}
+
+impl<T: FromLua> FromLua for Vec<T> {
+    unsafe fn from_lua(L: &mut RawState, idx: i32) -> Result<Vec<T>, FromLuaError> {
+        if !L.istable(idx) {
+            return Err(FromLuaError::mismatch(L, idx, "table"));
+        }
+        let idx = L.absindex(idx);
+        let len = L.objlen(idx);
+        let mut vec = Vec::with_capacity(len);
+        L.checkstack_(1);
+        for i in 1..len+1 {
+            L.rawgeti(idx, i as i32);
+            let val = T::from_lua(L, -1);
+            L.pop(1);
+            vec.push(val?);
+        }
+        Ok(vec)
+    }
+}
+
+impl<K: ToLua + Eq + Hash, V: ToLua> ToLua for HashMap<K,V> {
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+        L.createtable(0, self.len() as i32);
+        L.checkstack_(2);
+        for (key, val) in self.iter() {
+            push_one(L, key);
+            push_one(L, val);
+            L.rawset(-3);
+        }
+        1
+    }
+}
+
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K,V> {
+    unsafe fn from_lua(L: &mut RawState, idx: i32) -> Result<HashMap<K,V>, FromLuaError> {
+        if !L.istable(idx) {
+            return Err(FromLuaError::mismatch(L, idx, "table"));
+        }
+        let idx = L.absindex(idx);
+        let mut map = HashMap::new();
+        L.checkstack_(2);
+        L.pushnil();
+        while L.next(idx) {
+            let pair = K::from_lua(L, -2).and_then(|k| V::from_lua(L, -1).map(|v| (k, v)));
+            match pair {
+                Ok((k, v)) => { map.insert(k, v); }
+                Err(e) => {
+                    L.pop(2);
+                    return Err(e);
+                }
+            }
+            L.pop(1);
+        }
+        Ok(map)
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident)+) => (
+        impl<$($name: ToLua),+> ToLua for ($($name,)+) {
+            #[allow(non_snake_case)]
+            unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+                let ($(ref $name,)+) = *self;
+                let mut n = 0;
+                $(
+                    L.checkstack_(1);
+                    n += $name.push_lua(L);
+                )+
+                n
+            }
+        }
+
+        impl<$($name: FromLua),+> FromLua for ($($name,)+) {
+            #[allow(non_snake_case,unused_assignments)]
+            unsafe fn from_lua(L: &mut RawState, idx: i32) -> Result<($($name,)+), FromLuaError> {
+                let mut idx = L.absindex(idx);
+                $(
+                    let $name = $name::from_lua(L, idx)?;
+                    idx += 1;
+                )+
+                Ok(($($name,)+))
+            }
+        }
+    )
+}
+
+impl_tuple!(A);
+impl_tuple!(A B);
+impl_tuple!(A B C);
+impl_tuple!(A B C D);
+impl_tuple!(A B C D E);
+impl_tuple!(A B C D E F);
+impl_tuple!(A B C D E F G);
+impl_tuple!(A B C D E F G H);
@@ -0,0 +1,264 @@
+//! Line coverage built on set_hook(), with lcov output
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
+use std::mem;
+use std::rc::Rc;
+
+use {State, ExternState, HookEvent, Debug, DebugEvent, MASKCALL, MASKLINE};
+use backtrace::{file_name, debug_file_name};
+
+/// A collector of line coverage, counting the hits of every line run.
+///
+/// The executable lines of each function, and of all the functions nested
+/// in it, are recorded when it is first called, so the functions of a chunk
+/// that never run are reported as soon as the chunk runs. To include chunks
+/// that never run at all, pass them to add_function().
+///
+/// The collector uses the hook set with set_hook(), replacing any other hook
+/// while it runs.
+#[derive(Clone,Debug,Default)]
+pub struct Coverage {
+    recorder: Rc<RefCell<Recorder>>
+}
+
+/// The line coverage recorded by a Coverage between start() and stop()
+#[derive(Clone,PartialEq,Eq,Debug,Default)]
+pub struct CoverageReport {
+    files: BTreeMap<String, BTreeMap<i32, u64>>
+}
+
+#[derive(Debug,Default)]
+struct Recorder {
+    files: BTreeMap<String, BTreeMap<i32, u64>>,
+    /// The functions whose executable lines were recorded, by file and line
+    seen: HashSet<(String, i32)>
+}
+
+impl Coverage {
+    /// Returns a collector that has recorded nothing.
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Starts recording the lines run by `L`, discarding anything recorded
+    /// before. Coroutines created after start() are covered as well.
+    pub fn start(&self, L: &mut State) {
+        *self.recorder.borrow_mut() = Recorder::default();
+        let recorder = self.recorder.clone();
+        L.set_hook(MASKCALL | MASKLINE, 0, move |L, ev| unsafe {
+            let mut recorder = recorder.borrow_mut();
+            match ev.event() {
+                DebugEvent::HookCall => recorder.call(L, ev),
+                DebugEvent::HookLine => recorder.line(L, ev),
+                _ => ()
+            }
+        });
+    }
+
+    /// Records the executable lines of the Lua function on top of the stack
+    /// of `L` and of the functions nested in it, so they are reported even if
+    /// they never run. The function is popped.
+    ///
+    /// Fails the task if the value is not a function.
+    pub fn add_function(&self, L: &mut State) {
+        luaassert!(L, L.isfunction(-1), "add_function: function expected");
+        L.checkstack_(1);
+        let mut ar = Debug::new();
+        // getinfo() pops the function, so keep a copy for the lines
+        L.pushvalue(-1);
+        L.getinfo(">S", &mut ar);
+        unsafe {
+            let file = debug_file_name(&ar);
+            self.recorder.borrow_mut().add_function(L.as_extern(), file);
+        }
+    }
+
+    /// Stops recording, removing the hook, and returns what was recorded.
+    pub fn stop(&self, L: &mut State) -> CoverageReport {
+        L.remove_hook();
+        let recorder = mem::take(&mut *self.recorder.borrow_mut());
+        CoverageReport{ files: recorder.files }
+    }
+}
+
+impl CoverageReport {
+    /// Returns the hit count of every executable line, by file. Chunks loaded
+    /// from files are named by their path, and other chunks by their short
+    /// source, e.g. `[string "..."]`.
+    pub fn files(&self) -> &BTreeMap<String, BTreeMap<i32, u64>> {
+        &self.files
+    }
+
+    /// Returns the hit count of a line, or None if it is not known to be
+    /// executable.
+    pub fn hits(&self, file: &str, line: i32) -> Option<u64> {
+        self.files.get(file).and_then(|lines| lines.get(&line).cloned())
+    }
+
+    /// Writes the report in the lcov tracefile format (.info), as read by
+    /// genhtml and most coverage services.
+    pub fn write_lcov<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for (file, lines) in &self.files {
+            writeln!(w, "TN:")?;
+            writeln!(w, "SF:{}", file)?;
+            for (line, hits) in lines {
+                writeln!(w, "DA:{},{}", line, hits)?;
+            }
+            writeln!(w, "LF:{}", lines.len())?;
+            writeln!(w, "LH:{}", lines.values().filter(|&&hits| hits > 0).count())?;
+            writeln!(w, "end_of_record")?;
+        }
+        w.flush()
+    }
+}
+
+impl Recorder {
+    unsafe fn call(&mut self, L: &mut ExternState, ev: &HookEvent) {
+        let frame = ev.frame(L);
+        let file = file_name(&frame);
+        if frame.what == "C" || self.seen.contains(&(file.clone(), frame.linedefined)) {
+            return;
+        }
+        self.seen.insert((file.clone(), frame.linedefined));
+        L.checkstack_(1);
+        ev.getinfo(L, "f");
+        self.add_function(L, file);
+    }
+
+    unsafe fn line(&mut self, L: &mut ExternState, ev: &HookEvent) {
+        ev.getinfo(L, "Sl");
+        let file = debug_file_name(ev.debug());
+        let line = ev.line().unwrap_or(0);
+        *self.files.entry(file).or_default().entry(line).or_insert(0) += 1;
+    }
+
+    /// Records the executable lines of the Lua function on top of the stack,
+    /// and of the functions nested in it, as lines of `file`. Pops the
+    /// function.
+    unsafe fn add_function(&mut self, L: &mut ExternState, file: String) {
+        // the lines are in the debug information of the binary chunk
+        let mut chunk = Vec::new();
+        let functions = match L.dump_to(&mut chunk) {
+            Ok(()) => chunk_lines(&chunk).unwrap_or_default(),
+            Err(_) => Vec::new()
+        };
+        L.pop(1);
+        let lines = self.files.entry(file.clone()).or_default();
+        for (linedefined, valid) in functions {
+            self.seen.insert((file.clone(), linedefined));
+            for line in valid {
+                lines.entry(line).or_insert(0);
+            }
+        }
+    }
+}
+
+/// Returns the line of definition and the lines of the instructions of every
+/// function in a binary chunk made by dump(), the main function first.
+/// Returns None if the chunk is not a Lua 5.1 chunk.
+fn chunk_lines(chunk: &[u8]) -> Option<Vec<(i32, Vec<i32>)>> {
+    let header = chunk.get(..12)?;
+    if &header[..5] != b"\x1bLua\x51" {
+        return None;
+    }
+    let mut r = ChunkReader{
+        data: &chunk[12..],
+        little_endian: header[6] == 1,
+        int_size: header[7] as usize,
+        size_t_size: header[8] as usize,
+        instruction_size: header[9] as usize,
+        number_size: header[10] as usize
+    };
+    let mut functions = Vec::new();
+    r.function(&mut functions)?;
+    Some(functions)
+}
+
+/// A reader of the binary chunk format of lundump.c, with the type sizes
+/// given by the header of the chunk
+struct ChunkReader<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+    int_size: usize,
+    size_t_size: usize,
+    instruction_size: usize,
+    number_size: usize
+}
+
+impl<'a> ChunkReader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if n > self.data.len() {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    /// Reads an unsigned integer of `n` bytes.
+    fn uint(&mut self, n: usize) -> Option<u64> {
+        if n > 8 {
+            return None;
+        }
+        let bytes = self.bytes(n)?;
+        let fold = |v: u64, &b: &u8| v << 8 | b as u64;
+        Some(if self.little_endian { bytes.iter().rev().fold(0, fold) } else { bytes.iter().fold(0, fold) })
+    }
+
+    fn int(&mut self) -> Option<i32> {
+        let n = self.int_size;
+        self.uint(n).map(|v| v as i32)
+    }
+
+    fn count(&mut self) -> Option<usize> {
+        self.int().filter(|&n| n >= 0).map(|n| n as usize)
+    }
+
+    fn skip_string(&mut self) -> Option<()> {
+        let n = self.size_t_size;
+        let len = self.uint(n)?;
+        self.bytes(len as usize).map(|_| ())
+    }
+
+    /// Reads a function and the functions nested in it into `functions`.
+    fn function(&mut self, functions: &mut Vec<(i32, Vec<i32>)>) -> Option<()> {
+        self.skip_string()?;
+        let linedefined = self.int()?;
+        // lastlinedefined, then nups, numparams, is_vararg and maxstacksize
+        self.int()?;
+        self.bytes(4)?;
+        let ncode = self.count()?;
+        self.bytes(ncode.checked_mul(self.instruction_size)?)?;
+        for _ in 0..self.count()? {
+            match self.bytes(1)?[0] {
+                0 => (),
+                1 => { self.bytes(1)?; }
+                3 => { let n = self.number_size; self.bytes(n)?; }
+                4 => self.skip_string()?,
+                _ => return None
+            }
+        }
+        let index = functions.len();
+        functions.push((linedefined, Vec::new()));
+        for _ in 0..self.count()? {
+            self.function(functions)?;
+        }
+        let mut lines = Vec::new();
+        for _ in 0..self.count()? {
+            lines.push(self.int()?);
+        }
+        functions[index].1 = lines;
+        // local variables, and upvalue names
+        for _ in 0..self.count()? {
+            self.skip_string()?;
+            self.int()?;
+            self.int()?;
+        }
+        for _ in 0..self.count()? {
+            self.skip_string()?;
+        }
+        Some(())
+    }
+}
@@ -0,0 +1,570 @@
+//! A Debug Adapter Protocol server, built on Debugger
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use {State, ExternState, RawState, Frame, Error, Type, GLOBALSINDEX};
+use {Debugger, DebugHandler, Session, PauseReason, Resume, Variable};
+use convert::ToLua;
+use json::Json;
+
+/// A server for the Debug Adapter Protocol, so that editors such as VS Code
+/// can debug the Lua code of a State.
+///
+/// Chunks loaded from files are shown with their path, relative paths being
+/// resolved against the root directory. Each paused frame has three scopes
+/// of variables: its locals, its upvalues, and the globals.
+///
+/// Requests are read on a separate thread, and handled on the thread of the
+/// State: while the code is paused, or at the next line run.
+pub struct DapServer {
+    debugger: Debugger,
+    conn: Rc<RefCell<Connection>>
+}
+
+struct Connection {
+    messages: Receiver<Json>,
+    writer: Box<dyn Write>,
+    seq: i64,
+    root: PathBuf,
+    /// The breakpoints set for each source path, as file and line
+    breakpoints: HashMap<String, Vec<(String, i32)>>,
+    pause_requested: bool,
+    configured: bool,
+    disconnected: bool
+}
+
+struct Handler {
+    conn: Rc<RefCell<Connection>>
+}
+
+// Largest message body accepted, so that a client cannot make the server
+// allocate without bound
+const MAX_MESSAGE: usize = 16 << 20;
+// Longest header line accepted
+const MAX_HEADER: usize = 1024;
+
+// Scopes of variable references
+const LOCALS: i64 = 0;
+const UPVALUES: i64 = 1;
+const GLOBALS: i64 = 2;
+
+/// A value given to setVariable
+enum Literal {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String)
+}
+
+impl DapServer {
+    /// Returns a server reading requests from `reader` and writing responses
+    /// and events to `writer`.
+    pub fn new<R, W>(reader: R, writer: W) -> DapServer
+        where R: Read + Send + 'static, W: Write + 'static {
+        let (tx, rx) = mpsc::channel();
+        let conn = Rc::new(RefCell::new(Connection{
+            messages: rx,
+            writer: Box::new(writer),
+            seq: 0,
+            root: env::current_dir().unwrap_or_default(),
+            breakpoints: HashMap::new(),
+            pause_requested: false,
+            configured: false,
+            disconnected: false
+        }));
+        let debugger = Debugger::new(Handler{ conn: conn.clone() });
+        let handle = debugger.pause_handle();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            while let Ok(Some(body)) = read_body(&mut reader) {
+                // a malformed message is passed on as null, to be answered
+                // with an error
+                let msg = Json::parse(&String::from_utf8_lossy(&body)).unwrap_or(Json::Null);
+                if tx.send(msg).is_err() {
+                    break;
+                }
+                handle.wake();
+            }
+            // let the handler see the end of the connection
+            drop(tx);
+            handle.wake();
+        });
+        DapServer{ debugger: debugger, conn: conn }
+    }
+
+    /// Returns a server speaking over stdin and stdout. Output of the Lua
+    /// code, such as print(), must then go elsewhere.
+    pub fn stdio() -> DapServer {
+        DapServer::new(io::stdin(), io::stdout())
+    }
+
+    /// Waits for one client to connect to `addr`, e.g. "127.0.0.1:4711",
+    /// and returns a server speaking over its connection.
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<DapServer> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        Ok(DapServer::new(stream.try_clone()?, stream))
+    }
+
+    /// Sets the directory against which the paths of chunks loaded from
+    /// relative paths are resolved. Defaults to the current directory.
+    pub fn root<P: Into<PathBuf>>(self, dir: P) -> DapServer {
+        self.conn.borrow_mut().root = dir.into();
+        self
+    }
+
+    /// Attaches the debugger to `L`, then handles requests until the client
+    /// is done configuring breakpoints, so they are set before any code
+    /// runs.
+    pub fn attach(&self, L: &mut State) {
+        self.debugger.attach(L);
+        let mut conn = self.conn.borrow_mut();
+        while !conn.configured && !conn.disconnected {
+            match conn.messages.recv() {
+                Ok(msg) => { conn.handle(&self.debugger, None, msg); }
+                Err(_) => conn.disconnect(&self.debugger)
+            }
+        }
+    }
+
+    /// Calls a function in protected mode through Debugger::pcall(), so the
+    /// client can inspect the stack of errors.
+    pub fn pcall(&self, L: &mut State, nargs: i32, nresults: i32) -> Result<(),Error> {
+        self.debugger.pcall(L, nargs, nresults)
+    }
+
+    /// Detaches the debugger from `L`, and tells the client the debuggee has
+    /// terminated.
+    pub fn detach(&self, L: &mut State) {
+        self.debugger.detach(L);
+        let mut conn = self.conn.borrow_mut();
+        if !conn.disconnected {
+            conn.event("terminated", Json::object(vec![]));
+        }
+    }
+
+    /// Returns the underlying debugger.
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+}
+
+impl DebugHandler for Handler {
+    fn paused(&mut self, session: &mut Session, reason: PauseReason) -> Resume {
+        let mut conn = self.conn.borrow_mut();
+        if conn.disconnected {
+            return Resume::Continue;
+        }
+        let mut body = vec![
+            ("reason", Json::from(match reason {
+                PauseReason::Breakpoint(..) => "breakpoint",
+                PauseReason::Step => "step",
+                PauseReason::Requested => "pause",
+                PauseReason::Error(_) => "exception"
+            })),
+            ("threadId", Json::from(1)),
+            ("allThreadsStopped", Json::from(true))
+        ];
+        if let PauseReason::Error(message) = reason {
+            body.push(("text", Json::from(message)));
+        }
+        conn.event("stopped", Json::object(body));
+        conn.pause_requested = false;
+        let debugger = session.debugger().clone();
+        loop {
+            match conn.messages.recv() {
+                Ok(msg) => {
+                    if let Some(resume) = conn.handle(&debugger, Some(session), msg) {
+                        return resume;
+                    }
+                }
+                Err(_) => {
+                    conn.disconnect(&debugger);
+                    return Resume::Continue;
+                }
+            }
+        }
+    }
+
+    fn poll(&mut self, session: &mut Session) -> bool {
+        let mut conn = self.conn.borrow_mut();
+        let debugger = session.debugger().clone();
+        while !conn.disconnected {
+            match conn.messages.try_recv() {
+                Ok(msg) => { conn.handle(&debugger, None, msg); }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => conn.disconnect(&debugger)
+            }
+        }
+        let res = conn.pause_requested && !conn.disconnected;
+        conn.pause_requested = false;
+        res
+    }
+}
+
+impl Connection {
+    /// Handles a request. `session` is the paused stack, if any. Returns how
+    /// to resume after requests that resume.
+    fn handle(&mut self, debugger: &Debugger, session: Option<&mut Session>, msg: Json) -> Option<Resume> {
+        match msg {
+            Json::Object(_) => (),
+            _ => {
+                self.send(Json::object(vec![
+                    ("type", Json::from("response")),
+                    ("request_seq", Json::from(0)),
+                    ("success", Json::from(false)),
+                    ("command", Json::from("")),
+                    ("message", Json::from("invalid JSON message"))
+                ]));
+                return None;
+            }
+        }
+        let command = msg.get("command").and_then(Json::as_str).unwrap_or("").to_string();
+        if msg.get("type").and_then(Json::as_str) != Some("request") {
+            return None;
+        }
+        let args = msg.get("arguments").cloned().unwrap_or(Json::Null);
+        let paused = session.is_some();
+        let (res, resume) = match &command[..] {
+            "initialize" => (Ok(Json::object(vec![
+                ("supportsConfigurationDoneRequest", Json::from(true)),
+                ("supportsSetVariable", Json::from(true))
+            ])), None),
+            "launch" | "attach" => (Ok(Json::Null), None),
+            "setBreakpoints" => (self.set_breakpoints(debugger, &args), None),
+            "configurationDone" => {
+                self.configured = true;
+                (Ok(Json::Null), None)
+            }
+            "threads" => (Ok(Json::object(vec![
+                ("threads", Json::from(vec![Json::object(vec![
+                    ("id", Json::from(1)),
+                    ("name", Json::from("main"))
+                ])]))
+            ])), None),
+            "stackTrace" | "scopes" | "variables" | "setVariable" => match session {
+                Some(session) => (unsafe { self.inspect(session, &command, &args) }, None),
+                None => (Err("not paused".to_string()), None)
+            },
+            "continue" => (Ok(Json::object(vec![("allThreadsContinued", Json::from(true))])),
+                           Some(Resume::Continue)),
+            "next" => (Ok(Json::Null), Some(Resume::StepOver)),
+            "stepIn" => (Ok(Json::Null), Some(Resume::StepIn)),
+            "stepOut" => (Ok(Json::Null), Some(Resume::StepOut)),
+            "pause" => {
+                self.pause_requested = true;
+                (Ok(Json::Null), None)
+            }
+            "disconnect" => (Ok(Json::Null), Some(Resume::Continue)),
+            _ => (Err(format!("unsupported request: {}", command)), None)
+        };
+        let mut response = vec![
+            ("type", Json::from("response")),
+            ("request_seq", msg.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", Json::from(res.is_ok())),
+            ("command", Json::from(&command[..]))
+        ];
+        match res {
+            Ok(Json::Null) => (),
+            Ok(body) => response.push(("body", body)),
+            Err(message) => response.push(("message", Json::from(message)))
+        }
+        self.send(Json::object(response));
+        if command == "initialize" {
+            self.event("initialized", Json::object(vec![]));
+        }
+        if command == "disconnect" {
+            self.disconnect(debugger);
+        }
+        // requests that resume do nothing while running
+        if paused { resume } else { None }
+    }
+
+    /// Handles the requests that need a paused stack.
+    unsafe fn inspect(&mut self, session: &mut Session, command: &str, args: &Json) -> Result<Json, String> {
+        let int_arg = |name: &str| args.get(name).and_then(Json::as_i64);
+        match command {
+            "stackTrace" => {
+                let frames = session.frames();
+                let start = int_arg("startFrame").unwrap_or(0).max(0) as usize;
+                let levels = match int_arg("levels") {
+                    Some(n) if n > 0 => n as usize,
+                    _ => frames.len()
+                };
+                let stack_frames = frames.iter().enumerate().skip(start).take(levels)
+                    .map(|(level, frame)| self.stack_frame(level, frame))
+                    .collect::<Vec<_>>();
+                Ok(Json::object(vec![
+                    ("stackFrames", Json::from(stack_frames)),
+                    ("totalFrames", Json::from(frames.len() as i64))
+                ]))
+            }
+            "scopes" => {
+                let level = int_arg("frameId").ok_or("frameId expected")?;
+                let scope = |name: &str, scope: i64, expensive: bool| Json::object(vec![
+                    ("name", Json::from(name)),
+                    ("variablesReference", Json::from(level * 3 + scope + 1)),
+                    ("expensive", Json::from(expensive))
+                ]);
+                Ok(Json::object(vec![("scopes", Json::from(vec![
+                    scope("Locals", LOCALS, false),
+                    scope("Upvalues", UPVALUES, false),
+                    scope("Globals", GLOBALS, true)
+                ]))]))
+            }
+            "variables" => {
+                let (level, scope) = variables_ref(int_arg("variablesReference"))?;
+                let vars = variables(session, level, scope).ok_or("no such frame")?;
+                Ok(Json::object(vec![("variables", Json::from(vars.into_iter().map(|v| Json::object(vec![
+                    ("name", Json::from(v.name)),
+                    ("value", Json::from(v.value)),
+                    ("type", Json::from(v.type_name)),
+                    ("variablesReference", Json::from(0))
+                ])).collect::<Vec<_>>()))]))
+            }
+            "setVariable" => {
+                let (level, scope) = variables_ref(int_arg("variablesReference"))?;
+                let name = args.get("name").and_then(Json::as_str).ok_or("name expected")?;
+                let value = args.get("value").and_then(Json::as_str).ok_or("value expected")?;
+                let value = Literal::parse(value).ok_or("expected nil, a boolean, a number or a quoted string")?;
+                let set = if scope == GLOBALS {
+                    let L = session.state();
+                    L.checkstack_(1);
+                    L.push(value);
+                    L.setglobal(name);
+                    true
+                } else {
+                    let vars = variables(session, level, scope).ok_or("no such frame")?;
+                    let index = vars.iter().rev().find(|v| v.name == name).ok_or("no such variable")?.index;
+                    if scope == LOCALS {
+                        session.set_local(level, index, value)
+                    } else {
+                        session.set_upvalue(level, index, value)
+                    }
+                };
+                if !set {
+                    return Err("could not set the variable".to_string());
+                }
+                let vars = variables(session, level, scope).unwrap_or_default();
+                let var = vars.iter().rev().find(|v| v.name == name).ok_or("no such variable")?;
+                Ok(Json::object(vec![
+                    ("value", Json::from(&var.value[..])),
+                    ("type", Json::from(var.type_name))
+                ]))
+            }
+            _ => unreachable!()
+        }
+    }
+
+    fn stack_frame(&self, level: usize, frame: &Frame) -> Json {
+        let name = match (frame.name.as_ref(), &frame.what[..]) {
+            (Some(name), _) => name.clone(),
+            (None, "main") => "main chunk".to_string(),
+            (None, "C") => "[C]".to_string(),
+            (None, "tail") => "(tail call)".to_string(),
+            (None, _) => format!("function <{}:{}>", frame.short_src, frame.linedefined)
+        };
+        let mut members = vec![
+            ("id", Json::from(level as i64)),
+            ("name", Json::from(name)),
+            ("line", Json::from(frame.currentline.unwrap_or(0) as i64)),
+            ("column", Json::from(if frame.currentline.is_some() { 1 } else { 0 }))
+        ];
+        if frame.what != "C" {
+            let source = if frame.source.starts_with('@') {
+                let path = self.root.join(&frame.source[1..]);
+                Json::object(vec![
+                    ("name", Json::from(path.file_name().map_or(String::new(), |s| s.to_string_lossy().into_owned()))),
+                    ("path", Json::from(path.to_string_lossy().into_owned()))
+                ])
+            } else {
+                Json::object(vec![("name", Json::from(&frame.short_src[..]))])
+            };
+            members.push(("source", source));
+        }
+        Json::object(members)
+    }
+
+    fn set_breakpoints(&mut self, debugger: &Debugger, args: &Json) -> Result<Json, String> {
+        let source = args.get("source").ok_or("source expected")?;
+        let path = source.get("path").and_then(Json::as_str)
+            .or_else(|| source.get("name").and_then(Json::as_str))
+            .ok_or("source path expected")?;
+        for (file, line) in self.breakpoints.remove(path).unwrap_or_default() {
+            debugger.remove_breakpoint(&file, line);
+        }
+        let files = self.chunk_names(path);
+        let mut set = Vec::new();
+        let mut results = Vec::new();
+        for bp in args.get("breakpoints").and_then(Json::as_array).unwrap_or(&[]) {
+            let line = match bp.get("line").and_then(Json::as_i64) {
+                Some(line) => line as i32,
+                None => continue
+            };
+            for file in &files {
+                debugger.add_breakpoint(file, line);
+                set.push((file.clone(), line));
+            }
+            results.push(Json::object(vec![
+                ("verified", Json::from(true)),
+                ("line", Json::from(line as i64))
+            ]));
+        }
+        self.breakpoints.insert(path.to_string(), set);
+        Ok(Json::object(vec![("breakpoints", Json::from(results))]))
+    }
+
+    /// Returns the names a chunk loaded from `path` may have: the path
+    /// itself, and the path relative to the root.
+    fn chunk_names(&self, path: &str) -> Vec<String> {
+        let mut names = vec![path.to_string()];
+        if let Ok(rel) = Path::new(path).strip_prefix(&self.root) {
+            names.push(rel.to_string_lossy().into_owned());
+        }
+        names
+    }
+
+    /// Forgets the client: its breakpoints are removed and the code runs
+    /// freely.
+    fn disconnect(&mut self, debugger: &Debugger) {
+        self.disconnected = true;
+        self.pause_requested = false;
+        self.breakpoints.clear();
+        debugger.clear_breakpoints();
+        debugger.set_pause_on_error(false);
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.send(Json::object(vec![
+            ("type", Json::from("event")),
+            ("event", Json::from(event)),
+            ("body", body)
+        ]));
+    }
+
+    /// Sends a message, numbering it. Write errors are left to be noticed by
+    /// the reader.
+    fn send(&mut self, msg: Json) {
+        self.seq += 1;
+        let mut members = vec![("seq".to_string(), Json::from(self.seq))];
+        if let Json::Object(rest) = msg {
+            members.extend(rest);
+        }
+        let _ = write_message(&mut self.writer, &Json::Object(members));
+    }
+}
+
+/// Splits a variables reference into a level and a scope.
+fn variables_ref(reference: Option<i64>) -> Result<(i32, i64), String> {
+    match reference {
+        Some(r) if r > 0 => Ok((((r - 1) / 3) as i32, (r - 1) % 3)),
+        _ => Err("variablesReference expected".to_string())
+    }
+}
+
+unsafe fn variables(session: &mut Session, level: i32, scope: i64) -> Option<Vec<Variable>> {
+    match scope {
+        LOCALS => session.locals(level),
+        UPVALUES => session.upvalues(level),
+        _ => Some(globals(session.state()))
+    }
+}
+
+/// Returns the globals with string names, sorted by name.
+unsafe fn globals(L: &mut ExternState) -> Vec<Variable> {
+    L.checkstack_(3);
+    let mut vars = Vec::new();
+    L.pushnil();
+    while L.next(GLOBALSINDEX) {
+        if L.type_(-2) == Some(Type::String) {
+            L.pushvalue(-2);
+            let name = L.tostring(-1).unwrap_or("").to_string();
+            L.pop(1);
+            vars.push(Variable{
+                index: 0,
+                name: name,
+                type_name: L.typename(-1),
+                value: L.describe(-1)
+            });
+        }
+        L.pop(1);
+    }
+    vars.sort_by(|a, b| a.name.cmp(&b.name));
+    vars
+}
+
+impl Literal {
+    fn parse(s: &str) -> Option<Literal> {
+        let s = s.trim();
+        match s {
+            "nil" => Some(Literal::Nil),
+            "true" => Some(Literal::Bool(true)),
+            "false" => Some(Literal::Bool(false)),
+            _ if s.len() >= 2 && (s.starts_with('"') && s.ends_with('"') ||
+                                  s.starts_with('\'') && s.ends_with('\'')) => {
+                Some(Literal::String(s[1..s.len()-1].to_string()))
+            }
+            _ => s.parse().ok().map(Literal::Number)
+        }
+    }
+}
+
+impl ToLua for Literal {
+    unsafe fn push_lua(&self, L: &mut RawState) -> i32 {
+        match *self {
+            Literal::Nil => L.pushnil(),
+            Literal::Bool(b) => L.pushboolean(b),
+            Literal::Number(n) => L.pushnumber(n),
+            Literal::String(ref s) => L.pushstring(s)
+        }
+        1
+    }
+}
+
+/// Reads the body of a message framed by a Content-Length header. Returns
+/// None at the end of the input, and an error of kind InvalidData if the
+/// body is larger than MAX_MESSAGE or a header line longer than MAX_HEADER.
+pub fn read_body<R: BufRead>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        let n = r.by_ref().take(MAX_HEADER as u64).read_line(&mut line)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        if n == MAX_HEADER && !line.ends_with('\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "header line too long"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.unwrap_or(0);
+    if length > MAX_MESSAGE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large"));
+    }
+    let mut body = vec![0; length];
+    r.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+/// Writes a message framed by a Content-Length header.
+pub fn write_message<W: Write + ?Sized>(w: &mut W, msg: &Json) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(w, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    w.flush()
+}
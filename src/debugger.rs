@@ -0,0 +1,438 @@
+//! An embeddable debugger with breakpoints and stepping, built on set_hook()
+
+use libc;
+use libc::c_int;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use {State, ExternState, RawState, Frame, ToLua, Error};
+use {DebugEvent, MASKLINE};
+use raw;
+use error::pop_error;
+use convert::push_one;
+use backtrace::file_name;
+
+/// Receives control whenever a Debugger pauses the Lua code.
+pub trait DebugHandler {
+    /// Called while the code is paused, with a session for inspecting and
+    /// modifying the paused stack. Returns how to go on.
+    ///
+    /// Hooks do not run while the handler runs, so the debugger does not
+    /// stop in Lua code called from here.
+    fn paused(&mut self, session: &mut Session, reason: PauseReason) -> Resume;
+
+    /// Called at the next line run after PauseHandle::wake(), without
+    /// pausing. Returns true to pause with PauseReason::Requested.
+    ///
+    /// This lets a handler fed from another thread apply requests, such as
+    /// new breakpoints, while the code runs.
+    fn poll(&mut self, _session: &mut Session) -> bool {
+        false
+    }
+}
+
+/// Why a Debugger paused
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub enum PauseReason {
+    /// A breakpoint was hit at this file and line
+    Breakpoint(String, i32),
+    /// A step finished, or Debugger::pause() was called
+    Step,
+    /// A pause was requested through a PauseHandle or by poll()
+    Requested,
+    /// An error was raised with this message, inside Debugger::pcall()
+    Error(String)
+}
+
+/// How to go on after a pause
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum Resume {
+    /// Run until the next breakpoint
+    Continue,
+    /// Stop at the next line run, entering function calls
+    StepIn,
+    /// Stop at the next line of the paused function or its callers
+    StepOver,
+    /// Stop at the next line of a caller of the paused function
+    StepOut
+}
+
+/// A variable of a paused frame, as reported by Session
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Variable {
+    /// The index of the variable, as used by set_local() or set_upvalue()
+    pub index: i32,
+    /// The name of the variable
+    pub name: String,
+    /// The type of the value, e.g. "number"
+    pub type_name: &'static str,
+    /// The value, as formatted by describe()
+    pub value: String
+}
+
+/// The paused stack, as seen by a DebugHandler.
+///
+/// Levels count from the paused function (level 0) to its callers.
+pub struct Session<'a, 'l: 'a> {
+    L: &'a mut ExternState<'l>,
+    debugger: Debugger,
+    /// The stack level of the paused function
+    base: i32
+}
+
+/// A debugger for the Lua code of a State.
+///
+/// The debugger uses the hook set with set_hook(), replacing any other hook
+/// while it is attached. Clones share the breakpoints and the handler.
+#[derive(Clone)]
+pub struct Debugger {
+    inner: Rc<RefCell<Inner>>
+}
+
+/// A handle for pausing a Debugger from another thread. See pause_handle().
+#[derive(Clone,Debug)]
+pub struct PauseHandle {
+    requests: Arc<AtomicUsize>
+}
+
+// Bits of PauseHandle::requests
+const REQUEST_PAUSE: usize = 1;
+const REQUEST_POLL: usize = 2;
+
+impl PauseHandle {
+    /// Requests a pause at the next line run.
+    pub fn pause(&self) {
+        self.requests.fetch_or(REQUEST_PAUSE, Ordering::SeqCst);
+    }
+
+    /// Requests a call to DebugHandler::poll() at the next line run.
+    pub fn wake(&self) {
+        self.requests.fetch_or(REQUEST_POLL, Ordering::SeqCst);
+    }
+}
+
+struct Inner {
+    /// Lines with a breakpoint, by file
+    breakpoints: HashMap<String, BTreeSet<i32>>,
+    /// All lines with a breakpoint, to skip looking up the file of most lines
+    lines: HashSet<i32>,
+    step: Option<Step>,
+    pause_on_error: bool,
+    requests: Arc<AtomicUsize>,
+    /// The handler, taken out while it runs
+    handler: Option<Box<dyn DebugHandler>>
+}
+
+#[derive(Copy,Clone)]
+enum Step {
+    In,
+    /// Stop in this thread at this stack depth or less
+    Depth(usize, usize)
+}
+
+impl Debugger {
+    /// Returns a debugger that calls `handler` when it pauses. It pauses on
+    /// errors by default.
+    pub fn new<H: DebugHandler + 'static>(handler: H) -> Debugger {
+        Debugger{ inner: Rc::new(RefCell::new(Inner{
+            breakpoints: HashMap::new(),
+            lines: HashSet::new(),
+            step: None,
+            pause_on_error: true,
+            requests: Arc::new(AtomicUsize::new(0)),
+            handler: Some(Box::new(handler))
+        })) }
+    }
+
+    /// Starts watching the Lua code run by `L`, and its coroutines created
+    /// after this call.
+    pub fn attach(&self, L: &mut State) {
+        let inner = self.inner.clone();
+        L.set_hook(MASKLINE, 0, move |L, ev| unsafe {
+            if ev.event() != DebugEvent::HookLine {
+                return;
+            }
+            let line = ev.line().unwrap_or(0);
+            let requests = {
+                let requests = &inner.borrow().requests;
+                if requests.load(Ordering::SeqCst) != 0 { requests.swap(0, Ordering::SeqCst) } else { 0 }
+            };
+            let requested = requests & REQUEST_PAUSE != 0 ||
+                            requests & REQUEST_POLL != 0 && poll(&inner, L);
+            let reason = {
+                let inner = inner.borrow();
+                let stepped = match inner.step {
+                    None => false,
+                    Some(Step::In) => true,
+                    Some(Step::Depth(thread, depth)) => {
+                        thread == L.get_lua_State() as usize && stack_depth(L) <= depth
+                    }
+                };
+                if requested {
+                    Some(PauseReason::Requested)
+                } else if stepped {
+                    Some(PauseReason::Step)
+                } else if inner.lines.contains(&line) {
+                    let file = file_name(&ev.frame(L));
+                    if inner.breakpoints.get(&file).map_or(false, |lines| lines.contains(&line)) {
+                        Some(PauseReason::Breakpoint(file, line))
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            };
+            if let Some(reason) = reason {
+                pause(&inner, L, 0, reason);
+            }
+        });
+    }
+
+    /// Stops watching the Lua code of `L`, removing the hook.
+    pub fn detach(&self, L: &mut State) {
+        L.remove_hook();
+    }
+
+    /// Sets a breakpoint at `line` of `file`. Chunks loaded from files are
+    /// named by their path (without the '@'), and other chunks by their
+    /// short source.
+    pub fn add_breakpoint(&self, file: &str, line: i32) {
+        let mut inner = self.inner.borrow_mut();
+        inner.breakpoints.entry(file.to_string()).or_default().insert(line);
+        inner.lines.insert(line);
+    }
+
+    /// Removes the breakpoint at `line` of `file`. Returns false if there was
+    /// none.
+    pub fn remove_breakpoint(&self, file: &str, line: i32) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        let removed = inner.breakpoints.get_mut(file).map_or(false, |lines| lines.remove(&line));
+        inner.update_lines();
+        removed
+    }
+
+    /// Removes all breakpoints.
+    pub fn clear_breakpoints(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.breakpoints.clear();
+        inner.lines.clear();
+    }
+
+    /// Returns the breakpoints, as file and line.
+    pub fn breakpoints(&self) -> Vec<(String, i32)> {
+        let inner = self.inner.borrow();
+        let mut res: Vec<(String, i32)> = inner.breakpoints.iter()
+            .flat_map(|(file, lines)| lines.iter().map(move |&line| (file.clone(), line)))
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// Sets whether errors raised inside Debugger::pcall() pause the
+    /// debugger.
+    pub fn set_pause_on_error(&self, pause: bool) {
+        self.inner.borrow_mut().pause_on_error = pause;
+    }
+
+    /// Requests a pause at the next line run.
+    pub fn pause(&self) {
+        self.inner.borrow_mut().step = Some(Step::In);
+    }
+
+    /// Returns a handle that can pause the debugger from another thread.
+    pub fn pause_handle(&self) -> PauseHandle {
+        PauseHandle{ requests: self.inner.borrow().requests.clone() }
+    }
+
+    /// Calls a function in protected mode like pcall_(), but pauses the
+    /// debugger when the function raises an error, with the stack of the
+    /// error intact. Errors caught by pcall() within Lua do not pause.
+    pub fn pcall(&self, L: &mut State, nargs: i32, nresults: i32) -> Result<(),Error> {
+        luaassert!(L, nargs >= 0 && L.gettop() > nargs, "pcall: stack underflow");
+        L.checkstack_(1);
+        let base = L.gettop() - nargs;
+        L.pushlightuserdata(&self.inner as *const Rc<RefCell<Inner>> as *mut libc::c_void);
+        L.pushcclosure(error_handler, 1);
+        L.insert(base);
+        let res = L.pcall(nargs, nresults, base);
+        L.remove(base);
+        res.map_err(|e| unsafe { pop_error(&mut RawState::from_lua_State(L.get_lua_State()), e.into()) })
+    }
+}
+
+impl Inner {
+    fn update_lines(&mut self) {
+        self.breakpoints.retain(|_, lines| !lines.is_empty());
+        self.lines = self.breakpoints.values().flat_map(|lines| lines.iter().cloned()).collect();
+    }
+}
+
+/// Message handler of Debugger::pcall()
+unsafe extern "C" fn error_handler(L: *mut raw::lua_State) -> c_int {
+    let inner = &*(raw::lua_touserdata(L, raw::lua_upvalueindex(1)) as *const Rc<RefCell<Inner>>);
+    let mut state = ExternState::from_lua_State(L);
+    if inner.borrow().pause_on_error {
+        let message = state.describe(1);
+        // level 0 is this handler
+        pause(inner, &mut state, 1, PauseReason::Error(message));
+    }
+    1
+}
+
+/// Calls the handler, and sets up the step it asks for.
+unsafe fn pause(inner: &Rc<RefCell<Inner>>, L: &mut ExternState, base: i32, reason: PauseReason) {
+    let mut handler = match inner.borrow_mut().handler.take() {
+        Some(handler) => handler,
+        // a pause from within the handler
+        None => return
+    };
+    inner.borrow_mut().step = None;
+    let debugger = Debugger{ inner: inner.clone() };
+    let resume = handler.paused(&mut Session{ L: L, debugger: debugger, base: base }, reason);
+    let depth = stack_depth(L) - base as usize;
+    let thread = L.get_lua_State() as usize;
+    let mut inner = inner.borrow_mut();
+    inner.handler = Some(handler);
+    inner.step = match resume {
+        Resume::Continue => inner.step,
+        Resume::StepIn => Some(Step::In),
+        Resume::StepOver => Some(Step::Depth(thread, depth)),
+        Resume::StepOut => Some(Step::Depth(thread, depth.saturating_sub(1)))
+    };
+}
+
+/// Calls the poll() method of the handler.
+unsafe fn poll(inner: &Rc<RefCell<Inner>>, L: &mut ExternState) -> bool {
+    let mut handler = match inner.borrow_mut().handler.take() {
+        Some(handler) => handler,
+        None => return false
+    };
+    let debugger = Debugger{ inner: inner.clone() };
+    let res = handler.poll(&mut Session{ L: L, debugger: debugger, base: 0 });
+    inner.borrow_mut().handler = Some(handler);
+    res
+}
+
+/// Returns the number of levels of the stack of `L`.
+unsafe fn stack_depth(L: &mut ExternState) -> usize {
+    let mut level = 0;
+    while L.getstack(level).is_some() {
+        level += 1;
+    }
+    level as usize
+}
+
+impl<'a, 'l> Session<'a, 'l> {
+    /// Returns the frames of the paused stack, starting at level 0.
+    pub fn frames(&mut self) -> Vec<Frame> {
+        unsafe {
+            let mut frames = self.L.backtrace();
+            frames.drain(..self.base as usize);
+            frames
+        }
+    }
+
+    /// Returns the local variables of the function at `level`, or None if
+    /// there is no such level. Internal variables, whose names start with
+    /// '(', are left out.
+    pub fn locals(&mut self, level: i32) -> Option<Vec<Variable>> {
+        unsafe {
+            let ar = self.L.getstack(self.base + level)?;
+            self.L.checkstack_(2);
+            let mut vars = Vec::new();
+            let mut n = 1;
+            while let Some(name) = self.L.getlocal(&ar, n).map(|s| String::from_utf8_lossy(s).into_owned()) {
+                if !name.starts_with('(') {
+                    vars.push(self.variable(n, name));
+                }
+                self.L.pop(1);
+                n += 1;
+            }
+            Some(vars)
+        }
+    }
+
+    /// Sets local variable `index` of the function at `level` to `value`.
+    /// Returns false if there is no such level or variable.
+    pub fn set_local<T: ToLua>(&mut self, level: i32, index: i32, value: T) -> bool {
+        unsafe {
+            let mut ar = match self.L.getstack(self.base + level) {
+                Some(ar) => ar,
+                None => return false
+            };
+            self.L.checkstack_(1);
+            push_one(self.L.as_raw(), &value);
+            if self.L.setlocal(&mut ar, index).is_some() {
+                true
+            } else {
+                self.L.pop(1);
+                false
+            }
+        }
+    }
+
+    /// Returns the upvalues of the function at `level`, or None if there is
+    /// no such level.
+    pub fn upvalues(&mut self, level: i32) -> Option<Vec<Variable>> {
+        unsafe {
+            self.push_function(level)?;
+            self.L.checkstack_(2);
+            let mut vars = Vec::new();
+            let mut n = 1;
+            while let Some(name) = self.L.getupvalue(-1, n).map(|s| String::from_utf8_lossy(s).into_owned()) {
+                vars.push(self.variable(n, name));
+                self.L.pop(1);
+                n += 1;
+            }
+            self.L.pop(1);
+            Some(vars)
+        }
+    }
+
+    /// Sets upvalue `index` of the function at `level` to `value`. Returns
+    /// false if there is no such level or upvalue.
+    pub fn set_upvalue<T: ToLua>(&mut self, level: i32, index: i32, value: T) -> bool {
+        unsafe {
+            if self.push_function(level).is_none() {
+                return false;
+            }
+            self.L.checkstack_(1);
+            push_one(self.L.as_raw(), &value);
+            let res = self.L.setupvalue(-2, index).is_some();
+            self.L.pop(if res { 1 } else { 2 });
+            res
+        }
+    }
+
+    /// Returns the debugger, e.g. to change its breakpoints.
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    /// Returns the state of the paused code, for anything not covered by the
+    /// session. The stack must be left as it was found.
+    pub fn state(&mut self) -> &mut ExternState<'l> {
+        self.L
+    }
+
+    /// Describes the value on top of the stack as variable `index`.
+    unsafe fn variable(&mut self, index: i32, name: String) -> Variable {
+        Variable{
+            index: index,
+            name: name,
+            type_name: self.L.typename(-1),
+            value: self.L.describe(-1)
+        }
+    }
+
+    /// Pushes the function at `level`.
+    unsafe fn push_function(&mut self, level: i32) -> Option<()> {
+        let mut ar = self.L.getstack(self.base + level)?;
+        self.L.getinfo("f", &mut ar);
+        Some(())
+    }
+}
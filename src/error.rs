@@ -0,0 +1,361 @@
+//! A unified error type for loading and running Lua code
+
+use libc;
+use libc::c_int;
+use std::{error, fmt, io};
+use std::path::Path;
+
+use {State, ExternState, RawState, LuaRef, Reader, Type, MULTRET};
+use {LoadError, LoadFileError, PCallError, FromLuaError};
+use raw;
+use hooks::{is_budget_sentinel, take_interrupt_sentinel};
+use backtrace::{self, Frame, Traceback};
+use extra::extra;
+
+/// The kind of an `Error`
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum ErrorKind {
+    /// Syntax error during pre-compilation
+    Syntax,
+    /// Runtime error
+    Runtime,
+    /// Memory allocation error
+    Memory,
+    /// Error while running the error handler function
+    ErrorHandler,
+    /// Cannot read/open a file
+    File,
+    /// I/O error from a Rust reader or writer
+    Io,
+    /// The execution budget of pcall_budget() ran out
+    BudgetExceeded,
+    /// The code was stopped through an InterruptHandle
+    Interrupted,
+    /// A Lua value could not be converted to the requested Rust type
+    Conversion
+}
+
+impl From<LoadError> for ErrorKind {
+    fn from(err: LoadError) -> ErrorKind {
+        match err {
+            LoadError::ErrSyntax => ErrorKind::Syntax,
+            LoadError::ErrMem => ErrorKind::Memory
+        }
+    }
+}
+
+impl From<LoadFileError> for ErrorKind {
+    fn from(err: LoadFileError) -> ErrorKind {
+        match err {
+            LoadFileError::ErrSyntax => ErrorKind::Syntax,
+            LoadFileError::ErrMem => ErrorKind::Memory,
+            LoadFileError::ErrFile => ErrorKind::File
+        }
+    }
+}
+
+impl From<PCallError> for ErrorKind {
+    fn from(err: PCallError) -> ErrorKind {
+        match err {
+            PCallError::ErrRun => ErrorKind::Runtime,
+            PCallError::ErrMem => ErrorKind::Memory,
+            PCallError::ErrErr => ErrorKind::ErrorHandler
+        }
+    }
+}
+
+/// An error raised while loading or running Lua code.
+///
+/// Unlike `LoadError` and `PCallError`, the error object is taken off the
+/// stack. A string error object becomes the message. Any other error object
+/// is kept in the registry and the message describes its type.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+    value: Option<LuaRef>,
+    traceback: Option<String>,
+    backtrace: Option<Vec<Frame>>,
+    io: Option<io::Error>
+}
+
+impl Error {
+    /// Returns a new error with the given kind and message, and no error
+    /// object or traceback.
+    pub fn new(kind: ErrorKind, message: &str) -> Error {
+        Error{
+            kind: kind,
+            message: message.to_string(),
+            value: None,
+            traceback: None,
+            backtrace: None,
+            io: None
+        }
+    }
+
+    /// Pops the error object from the top of the stack of `L`.
+    unsafe fn pop(L: &mut RawState, mut kind: ErrorKind, backtrace: Option<Vec<Frame>>) -> Error {
+        let (message, value) = match L.type_(-1) {
+            Some(Type::LightUserdata) if is_budget_sentinel(L, -1) => {
+                L.pop(1);
+                kind = ErrorKind::BudgetExceeded;
+                ("execution budget exceeded".to_string(), None)
+            }
+            Some(Type::LightUserdata) if take_interrupt_sentinel(L, -1) => {
+                L.pop(1);
+                kind = ErrorKind::Interrupted;
+                ("interrupted".to_string(), None)
+            }
+            Some(Type::String) | Some(Type::Number) => {
+                let msg = String::from_utf8_lossy(L.tobytes(-1).unwrap_or(&[])).into_owned();
+                L.pop(1);
+                (msg, None)
+            }
+            Some(Type::Nil) | None => {
+                L.pop(1);
+                ("(error object is a nil value)".to_string(), None)
+            }
+            _ => {
+                let msg = format!("(error object is a {} value)", L.typename(-1));
+                (msg, Some(L.newref()))
+            }
+        };
+        Error{
+            kind: kind,
+            message: message,
+            value: value,
+            traceback: backtrace.as_ref().map(|frames| Traceback(frames).to_string()),
+            backtrace: backtrace,
+            io: None
+        }
+    }
+
+    /// Returns the kind of the error.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Returns the error message.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns a reference to the error object, if it was not a string.
+    /// Use pushref() to push the object onto the stack.
+    pub fn value(&self) -> Option<&LuaRef> {
+        self.value.as_ref()
+    }
+
+    /// Returns the stack traceback, if one was requested from pcall_().
+    pub fn traceback(&self) -> Option<&str> {
+        self.traceback.as_ref().map(|s| &s[..])
+    }
+
+    /// Returns the frames of the stack at the point of the error, if a
+    /// traceback was requested from pcall_().
+    pub fn backtrace(&self) -> Option<&[Frame]> {
+        self.backtrace.as_ref().map(|v| &v[..])
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)?;
+        if let Some(ref tb) = self.traceback {
+            write!(f, "\n{}", tb)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.io.as_ref().map(|e| e as &(dyn error::Error + 'static))
+    }
+}
+
+impl From<FromLuaError> for Error {
+    fn from(err: FromLuaError) -> Error {
+        Error::new(ErrorKind::Conversion, &err.to_string())
+    }
+}
+
+/// Pops the error object from the top of the stack of `L` as an Error.
+pub unsafe fn pop_error(L: &mut RawState, kind: ErrorKind) -> Error {
+    Error::pop(L, kind, None)
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error{
+            kind: ErrorKind::Io,
+            message: err.to_string(),
+            value: None,
+            traceback: None,
+            backtrace: None,
+            io: Some(err)
+        }
+    }
+}
+
+// Variants returning Error
+impl State {
+    /// Calls a function in protected mode, like pcall(), but returns the error
+    /// as an Error instead of leaving it on the stack.
+    ///
+    /// If `traceback` is true, a built-in message handler records the stack
+    /// traceback at the point of the error, in the format of
+    /// debug.traceback().
+    pub fn pcall_(&mut self, nargs: i32, nresults: i32, traceback: bool) -> Result<(),Error> {
+        #![inline(always)]
+        unsafe { self.as_extern().pcall_(nargs, nresults, traceback) }
+    }
+
+    /// Variant of loadfile() that returns the error as an Error.
+    pub fn loadfile_(&mut self, filename: Option<&Path>) -> Result<(),Error> {
+        #![inline(always)]
+        unsafe { self.as_extern().loadfile_(filename) }
+    }
+
+    /// Variant of loadbuffer() that returns the error as an Error.
+    /// Fails the task if `name` has any interior NULs.
+    pub fn loadbuffer_(&mut self, buf: &str, name: &str) -> Result<(),Error> {
+        #![inline(always)]
+        unsafe { self.as_extern().loadbuffer_(buf, name) }
+    }
+
+    /// Variant of loadstring() that returns the error as an Error.
+    /// Fails the task if `s` has any interior NULs.
+    pub fn loadstring_(&mut self, s: &str) -> Result<(),Error> {
+        #![inline(always)]
+        unsafe { self.as_extern().loadstring_(s) }
+    }
+
+    /// Loads and runs the given file, returning all of its results on the
+    /// stack, or an Error.
+    pub fn dofile_(&mut self, filename: Option<&Path>) -> Result<(),Error> {
+        #![inline(always)]
+        unsafe { self.as_extern().dofile_(filename) }
+    }
+
+    /// Loads and runs the given string, returning all of its results on the
+    /// stack, or an Error.
+    /// Fails the task if `s` has any interior NULs.
+    pub fn dostring_(&mut self, s: &str) -> Result<(),Error> {
+        #![inline(always)]
+        unsafe { self.as_extern().dostring_(s) }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn pcall_(&mut self, nargs: i32, nresults: i32, traceback: bool)
+                        -> Result<(),Error> {
+        luaassert!(self, nargs >= 0, "pcall_: invalid nargs");
+        luaassert!(self, nresults == MULTRET || nresults >= 0, "pcall_: invalid nresults");
+        luaassert!(self, self.gettop() > nargs, "pcall_: stack underflow");
+        // the message handler, below the results
+        self.checkstack_(1);
+        if nresults > nargs + 1 { self.checkstack_(nresults - nargs) }
+        self.as_raw().pcall_(nargs, nresults, traceback)
+    }
+
+    pub unsafe fn load_(&mut self, reader: Reader, data: *mut libc::c_void, chunkname: &str)
+                       -> Result<(),Error> {
+        self.checkstack_(1);
+        self.as_raw().load_(reader, data, chunkname)
+    }
+
+    pub unsafe fn loadfile_(&mut self, filename: Option<&Path>) -> Result<(),Error> {
+        self.checkstack_(1);
+        self.as_raw().loadfile_(filename)
+    }
+
+    pub unsafe fn loadbuffer_(&mut self, buf: &str, name: &str) -> Result<(),Error> {
+        self.checkstack_(1);
+        self.as_raw().loadbuffer_(buf, name)
+    }
+
+    pub unsafe fn loadstring_(&mut self, s: &str) -> Result<(),Error> {
+        self.checkstack_(1);
+        self.as_raw().loadstring_(s)
+    }
+
+    pub unsafe fn dofile_(&mut self, filename: Option<&Path>) -> Result<(),Error> {
+        self.checkstack_(1);
+        self.as_raw().dofile_(filename)
+    }
+
+    pub unsafe fn dostring_(&mut self, s: &str) -> Result<(),Error> {
+        self.checkstack_(1);
+        self.as_raw().dostring_(s)
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn pcall_(&mut self, nargs: i32, nresults: i32, traceback: bool)
+                        -> Result<(),Error> {
+        if !traceback {
+            return self.pcall(nargs, nresults, 0).map_err(|e| Error::pop(self, e.into(), None));
+        }
+        let base = self.gettop() - nargs;
+        self.pushcfunction(traceback_handler);
+        self.insert(base);
+        let res = self.pcall(nargs, nresults, base);
+        self.remove(base);
+        res.map_err(|e| {
+            let tb = self.take_traceback();
+            Error::pop(self, e.into(), tb)
+        })
+    }
+
+    pub unsafe fn load_(&mut self, reader: Reader, data: *mut libc::c_void, chunkname: &str)
+                       -> Result<(),Error> {
+        self.load(reader, data, chunkname).map_err(|e| Error::pop(self, e.into(), None))
+    }
+
+    pub unsafe fn loadfile_(&mut self, filename: Option<&Path>) -> Result<(),Error> {
+        let top = self.gettop();
+        self.loadfile(filename).map_err(|e| {
+            if self.gettop() == top {
+                // loadfile() rejects names with interior NULs without a message
+                let name = filename.map_or("stdin".into(), |p| p.to_string_lossy());
+                self.pushstring(&format!("cannot open {}", name));
+            }
+            Error::pop(self, e.into(), None)
+        })
+    }
+
+    pub unsafe fn loadbuffer_(&mut self, buf: &str, name: &str) -> Result<(),Error> {
+        self.loadbuffer(buf, name).map_err(|e| Error::pop(self, e.into(), None))
+    }
+
+    pub unsafe fn loadstring_(&mut self, s: &str) -> Result<(),Error> {
+        self.loadstring(s).map_err(|e| Error::pop(self, e.into(), None))
+    }
+
+    pub unsafe fn dofile_(&mut self, filename: Option<&Path>) -> Result<(),Error> {
+        self.loadfile_(filename)?;
+        self.pcall_(0, MULTRET, false)
+    }
+
+    pub unsafe fn dostring_(&mut self, s: &str) -> Result<(),Error> {
+        self.loadstring_(s)?;
+        self.pcall_(0, MULTRET, false)
+    }
+
+    /// Takes the backtrace recorded by traceback_handler, if any.
+    unsafe fn take_traceback(&mut self) -> Option<Vec<Frame>> {
+        (*extra(self)).traceback.take()
+    }
+}
+
+/// Message handler for pcall_() that records the backtrace in the Extra and
+/// returns the error object unchanged.
+unsafe extern "C" fn traceback_handler(L: *mut raw::lua_State) -> c_int {
+    let mut L = RawState::from_lua_State(L);
+    let frames = backtrace::frames(&mut L, 1);
+    (*extra(&mut L)).traceback = Some(frames);
+    1
+}
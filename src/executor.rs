@@ -0,0 +1,359 @@
+//! Rust futures called from Lua coroutines, and a single-threaded executor
+
+use libc::c_int;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+
+use {State, ExternState, RawState, Thread, ToLua, FromLua, PCallError, Error, ErrorKind};
+use {UserData, Type};
+use raw;
+use error::pop_error;
+
+// Wraps the Rust side of an async function. `call` returns a PendingCall (or
+// false and a message), `yield` suspends the coroutine on a PendingCall, and
+// `finish` turns the values the coroutine is resumed with into results or an
+// error raised at the position of the caller.
+const WRAPPER: &'static str = "local call, yield, finish = ...
+return function(...) return finish(yield(call(...))) end";
+
+/// Pushes the outcome of a future onto the stack of the waiting coroutine:
+/// true and the results, or false and an error message.
+type Completion = Box<dyn FnOnce(&mut RawState) -> i32>;
+
+type BoxedFuture = Pin<Box<dyn Future<Output = Completion>>>;
+
+/// The future of an async function call, yielded to the executor
+struct PendingCall {
+    future: Option<BoxedFuture>
+}
+
+impl UserData for PendingCall {
+    const NAME: &'static str = "PendingCall";
+}
+
+/// Adapts the future of an async function to produce a Completion
+struct Completing<F> {
+    future: F
+}
+
+impl<F, R, E> Future for Completing<F>
+        where F: Future<Output = Result<R, E>>, R: ToLua + 'static, E: fmt::Display {
+    type Output = Completion;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Completion> {
+        // the future is never moved out of its Completing
+        let future = unsafe { self.map_unchecked_mut(|c| &mut c.future) };
+        match future.poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(vals)) => Poll::Ready(Box::new(move |L: &mut RawState| unsafe {
+                L.pushboolean(true);
+                1 + L.push(vals)
+            })),
+            Poll::Ready(Err(err)) => {
+                let msg = err.to_string();
+                Poll::Ready(Box::new(move |L: &mut RawState| unsafe {
+                    L.pushboolean(false);
+                    L.pushstring(&msg);
+                    2
+                }))
+            }
+        }
+    }
+}
+
+impl State {
+    /// Pushes a Rust async function onto the stack as a Lua function.
+    ///
+    /// When called from Lua, `f` receives the arguments converted to `A` (a
+    /// tuple reads one argument each) and returns a future. The calling
+    /// coroutine is suspended until the future completes, and then receives
+    /// its results. An `Err`, or arguments that cannot be converted, is
+    /// raised as a Lua error with the position of the caller.
+    ///
+    /// The function must be called from a coroutine run by an Executor, and
+    /// not across a C call boundary such as pcall(). Other coroutines see it
+    /// yield an opaque PendingCall value.
+    pub fn push_async<F, A, Fut, R, E>(&mut self, f: F)
+            where F: Fn(A) -> Fut + 'static, A: FromLua, Fut: Future<Output = Result<R, E>> + 'static,
+                  R: ToLua + 'static, E: fmt::Display + 'static {
+        #![inline(always)]
+        unsafe { self.as_extern().push_async(f) }
+    }
+
+    /// Sets the Rust async function `f` as the new value of global `name`.
+    /// See push_async() for details.
+    ///
+    /// Fails the task if `name` has interior NULs.
+    pub fn register_async<F, A, Fut, R, E>(&mut self, name: &str, f: F)
+            where F: Fn(A) -> Fut + 'static, A: FromLua, Fut: Future<Output = Result<R, E>> + 'static,
+                  R: ToLua + 'static, E: fmt::Display + 'static {
+        #![inline(always)]
+        unsafe { self.as_extern().register_async(name, f) }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn push_async<F, A, Fut, R, E>(&mut self, f: F)
+            where F: Fn(A) -> Fut + 'static, A: FromLua, Fut: Future<Output = Result<R, E>> + 'static,
+                  R: ToLua + 'static, E: fmt::Display + 'static {
+        // the wrapper and its 3 arguments
+        self.checkstack_(4);
+        self.as_raw().push_async(f)
+    }
+
+    pub unsafe fn register_async<F, A, Fut, R, E>(&mut self, name: &str, f: F)
+            where F: Fn(A) -> Fut + 'static, A: FromLua, Fut: Future<Output = Result<R, E>> + 'static,
+                  R: ToLua + 'static, E: fmt::Display + 'static {
+        self.checkstack_(4);
+        self.as_raw().register_async(name, f)
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn push_async<F, A, Fut, R, E>(&mut self, f: F)
+            where F: Fn(A) -> Fut + 'static, A: FromLua, Fut: Future<Output = Result<R, E>> + 'static,
+                  R: ToLua + 'static, E: fmt::Display + 'static {
+        if self.loadbuffer(WRAPPER, "=async").is_err() {
+            // only a memory error is possible
+            self.error()
+        }
+        self.push_closure(move |L: &mut ExternState| {
+            match L.read::<A>(1) {
+                Ok(args) => {
+                    let future: BoxedFuture = Box::pin(Completing{ future: f(args) });
+                    L.push_userdata(PendingCall{ future: Some(future) });
+                    1
+                }
+                Err(err) => {
+                    L.pushboolean(false);
+                    L.pushstring(&format!("bad argument ({})", err));
+                    2
+                }
+            }
+        });
+        self.pushcfunction(yield_pending);
+        self.pushcfunction(finish_call);
+        self.call(3, 1);
+    }
+
+    pub unsafe fn register_async<F, A, Fut, R, E>(&mut self, name: &str, f: F)
+            where F: Fn(A) -> Fut + 'static, A: FromLua, Fut: Future<Output = Result<R, E>> + 'static,
+                  R: ToLua + 'static, E: fmt::Display + 'static {
+        self.push_async(f);
+        self.setglobal(name)
+    }
+}
+
+unsafe extern "C" fn yield_pending(L: *mut raw::lua_State) -> c_int {
+    let mut state = RawState::from_lua_State(L);
+    if state.to_userdata::<PendingCall>(1).is_some() {
+        state.yield_(1)
+    } else {
+        // the call failed before it could yield
+        state.gettop() as c_int
+    }
+}
+
+unsafe extern "C" fn finish_call(L: *mut raw::lua_State) -> c_int {
+    let mut state = RawState::from_lua_State(L);
+    if state.toboolean(1) {
+        return (state.gettop() - 1) as c_int;
+    }
+    state.settop(2);
+    if state.type_(2) == Some(Type::String) {
+        // level 1 is the wrapper, level 2 the caller of the async function
+        state.where_(2);
+        state.insert(-2);
+        state.concat(2);
+    }
+    state.error()
+}
+
+/// A single-threaded executor that runs Lua functions as coroutines and
+/// drives the futures of the async functions they call.
+///
+/// A task that calls an async function is resumed once its future completes,
+/// and a task that yields any other values is resumed with no values on the
+/// next round. The executor must always be used with the same State.
+pub struct Executor {
+    tasks: HashMap<usize, Task>,
+    next_id: usize,
+    shared: Arc<Shared>
+}
+
+struct Task {
+    thread: Thread,
+    /// The number of values to resume the coroutine with, if it is not
+    /// waiting on a future
+    nargs: i32,
+    future: Option<BoxedFuture>
+}
+
+/// The state shared with the wakers
+struct Shared {
+    ready: Mutex<VecDeque<usize>>,
+    runner: Mutex<Option<thread::Thread>>
+}
+
+struct TaskWaker {
+    id: usize,
+    shared: Arc<Shared>
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.shared.ready.lock().unwrap().push_back(self.id);
+        if let Some(ref runner) = *self.shared.runner.lock().unwrap() {
+            runner.unpark();
+        }
+    }
+}
+
+impl Executor {
+    /// Returns an executor with no tasks.
+    pub fn new() -> Executor {
+        Executor{
+            tasks: HashMap::new(),
+            next_id: 0,
+            shared: Arc::new(Shared{ ready: Mutex::new(VecDeque::new()), runner: Mutex::new(None) })
+        }
+    }
+
+    /// Pops a function and its `nargs` arguments from the stack of `L`, and
+    /// adds a task that calls it in a new coroutine. The task starts on the
+    /// next run().
+    pub fn spawn(&mut self, L: &mut State, nargs: i32) {
+        luaassert!(L, nargs >= 0 && L.gettop() > nargs, "spawn: stack underflow");
+        luaassert!(L, L.isfunction(-nargs-1), "spawn: function expected");
+        L.checkstack_(1);
+        // new_thread() takes the function from the top
+        L.pushvalue(-nargs-1);
+        L.remove(-nargs-2);
+        let th = L.new_thread();
+        unsafe {
+            let co = coroutine(L, &th);
+            raw::lua_xmove(L.get_lua_State(), co, nargs as c_int);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.tasks.insert(id, Task{ thread: th, nargs: nargs, future: None });
+        self.shared.ready.lock().unwrap().push_back(id);
+    }
+
+    /// Returns the number of unfinished tasks.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Returns true if all tasks have finished.
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Runs the tasks until they have all finished, blocking the current
+    /// thread while every task waits on a future.
+    ///
+    /// Stops at the first task that fails, and returns its error; the
+    /// failed task is removed, and the others are resumed by the next call.
+    pub fn run(&mut self, L: &mut State) -> Result<(),Error> {
+        *self.shared.runner.lock().unwrap() = Some(thread::current());
+        let res = self.run_tasks(L);
+        *self.shared.runner.lock().unwrap() = None;
+        res
+    }
+
+    fn run_tasks(&mut self, L: &mut State) -> Result<(),Error> {
+        loop {
+            let next = self.shared.ready.lock().unwrap().pop_front();
+            match next {
+                Some(id) => self.step(L, id)?,
+                None if self.tasks.is_empty() => return Ok(()),
+                // woken by the waker of any task, or spuriously
+                None => thread::park()
+            }
+        }
+    }
+
+    /// Polls the future of task `id`, or resumes its coroutine.
+    fn step(&mut self, L: &mut State, id: usize) -> Result<(),Error> {
+        let shared = self.shared.clone();
+        let (co, nargs) = match self.tasks.get_mut(&id) {
+            // a stale wakeup of a finished task
+            None => return Ok(()),
+            Some(task) => unsafe {
+                let co = coroutine(L, &task.thread);
+                let nargs = match task.future.as_mut() {
+                    None => task.nargs,
+                    Some(future) => {
+                        let waker = Waker::from(Arc::new(TaskWaker{ id: id, shared: shared }));
+                        match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                            Poll::Pending => return Ok(()),
+                            Poll::Ready(completion) => {
+                                let mut state = RawState::from_lua_State(co);
+                                luaassert!(L, state.checkstack(2), "run: cannot grow stack");
+                                state.settop(0);
+                                completion(&mut state)
+                            }
+                        }
+                    }
+                };
+                task.future = None;
+                (co, nargs)
+            }
+        };
+
+        unsafe {
+            let mut state = RawState::from_lua_State(co);
+            match raw::lua_resume(co, nargs as c_int) {
+                0 => {
+                    state.settop(0);
+                    self.tasks.remove(&id);
+                }
+                raw::LUA_YIELD => {
+                    let task = self.tasks.get_mut(&id).unwrap();
+                    let future = match state.to_userdata::<PendingCall>(-1) {
+                        Some(pending) => pending.future.take(),
+                        None => None
+                    };
+                    state.settop(0);
+                    task.nargs = 0;
+                    task.future = future;
+                    // poll the new future right away, or resume on the next round
+                    self.shared.ready.lock().unwrap().push_back(id);
+                }
+                code => {
+                    self.tasks.remove(&id);
+                    let kind = PCallError::from_code(code).map_or(ErrorKind::Runtime, ErrorKind::from);
+                    return Err(pop_error(&mut state, kind));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Executor {
+        Executor::new()
+    }
+}
+
+/// Returns the lua_State of the coroutine of `th`.
+unsafe fn coroutine(L: &mut State, th: &Thread) -> *mut raw::lua_State {
+    L.checkstack_(1);
+    L.pushref(th.anchor());
+    let co = raw::lua_tothread(L.get_lua_State(), -1);
+    L.pop(1);
+    co
+}
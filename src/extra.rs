@@ -0,0 +1,66 @@
+//! Rust-side data attached to a Lua state
+
+use libc;
+use libc::c_int;
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::{mem, ptr};
+
+use {RawState, REGISTRYINDEX};
+use hooks::HookState;
+use backtrace::Frame;
+use raw;
+
+/// Rust data shared by a Lua state and all of its threads.
+///
+/// It lives in a userdata in the registry, so it is dropped when the state is
+/// closed.
+pub struct Extra {
+    /// Registry references released by dropped LuaRefs, waiting to be unref'd
+    pub refs: Arc<Mutex<Vec<i32>>>,
+    /// Registry references of the metatables of UserData types
+    pub metatables: HashMap<TypeId, i32>,
+    /// State of the hooks installed by this crate
+    pub hooks: HookState,
+    /// The backtrace recorded by the message handler of pcall_()
+    pub traceback: Option<Vec<Frame>>
+}
+
+// The address of this static is the registry key for the Extra userdata
+static EXTRA_KEY: u8 = 0;
+
+/// Returns the Extra of the state `L`, creating it on first use.
+///
+/// The pointer stays valid until the state is closed.
+pub unsafe fn extra(L: &mut RawState) -> *mut Extra {
+    let key = &EXTRA_KEY as *const u8 as *mut libc::c_void;
+    L.checkstack_(3);
+    L.pushlightuserdata(key);
+    L.rawget(REGISTRYINDEX);
+    let mut p = L.touserdata(-1) as *mut Extra;
+    L.pop(1);
+    if p.is_null() {
+        p = L.newuserdata(mem::size_of::<Extra>()) as *mut Extra;
+        ptr::write(p, Extra{
+            refs: Arc::new(Mutex::new(Vec::new())),
+            metatables: HashMap::new(),
+            hooks: HookState::default(),
+            traceback: None
+        });
+        L.createtable(0, 1);
+        L.pushcfunction(extra_gc);
+        L.setfield(-2, "__gc");
+        L.setmetatable(-2);
+        L.pushlightuserdata(key);
+        L.insert(-2);
+        L.rawset(REGISTRYINDEX);
+    }
+    p
+}
+
+unsafe extern "C" fn extra_gc(L: *mut raw::lua_State) -> c_int {
+    let p = raw::lua_touserdata(L, 1) as *mut Extra;
+    ptr::drop_in_place(p);
+    0
+}
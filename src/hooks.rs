@@ -0,0 +1,418 @@
+//! Execution budgets and interrupts enforced by a count hook
+
+use libc;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use {State, ExternState, RawState, Error, Hook, Debug, DebugEvent, Frame, MASKCOUNT, MASKRET};
+use raw;
+use extra::extra;
+use macros::push_panic_message;
+
+/// Limits on the execution of a call made with pcall_budget().
+///
+/// Any limit left as None is not enforced.
+#[derive(Copy,Clone,PartialEq,Eq,Debug,Default)]
+pub struct Budget {
+    /// The maximum number of VM instructions executed
+    pub instructions: Option<u64>,
+    /// The maximum wall-clock time taken
+    pub time: Option<Duration>
+}
+
+impl Budget {
+    /// Returns a budget of `n` VM instructions.
+    pub fn instructions(n: u64) -> Budget {
+        Budget{ instructions: Some(n), time: None }
+    }
+
+    /// Returns a budget of `time` of wall-clock time.
+    pub fn time(time: Duration) -> Budget {
+        Budget{ instructions: None, time: Some(time) }
+    }
+}
+
+/// The budget of the running pcall_budget(), kept in the Extra
+#[derive(Copy,Clone)]
+pub struct BudgetState {
+    remaining: Option<u64>,
+    deadline: Option<Instant>
+}
+
+/// A handle for interrupting the Lua code running in a State from another
+/// thread. See interrupt_handle().
+#[derive(Clone,Debug)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>
+}
+
+impl InterruptHandle {
+    /// Requests that the running Lua code stop with an error of kind
+    /// Interrupted at the next hook point.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if an interrupt is pending.
+    pub fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Withdraws a pending interrupt.
+    pub fn clear(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+}
+
+/// An event passed to a hook closure installed with set_hook()
+pub struct HookEvent {
+    event: DebugEvent,
+    ar: *mut Debug
+}
+
+impl HookEvent {
+    /// Returns the kind of the event.
+    pub fn event(&self) -> DebugEvent {
+        self.event
+    }
+
+    /// Returns the new line for a HookLine event, or None.
+    pub fn line(&self) -> Option<i32> {
+        match self.event {
+            DebugEvent::HookLine => Some(unsafe { (*self.ar).currentline }),
+            _ => None
+        }
+    }
+
+    /// Returns the function running at the time of the event. The state must
+    /// be the one passed to the hook along with the event.
+    ///
+    /// For HookTailRet events the function is gone, and the frame says
+    /// nothing useful.
+    pub fn frame(&self, L: &mut ExternState) -> Frame {
+        unsafe {
+            L.getinfo("Snl", &mut *self.ar);
+            Frame::from_debug(&*self.ar)
+        }
+    }
+
+    /// Calls getinfo() on the activation record of the event, which fills it
+    /// in or pushes values as described there.
+    pub unsafe fn getinfo(&self, L: &mut ExternState, what: &str) -> bool {
+        L.getinfo(what, &mut *self.ar)
+    }
+
+    /// Returns the activation record of the event, for use with getlocal().
+    pub fn debug(&self) -> &Debug {
+        unsafe { &*self.ar }
+    }
+}
+
+type HookFn = Box<dyn FnMut(&mut ExternState, &HookEvent)>;
+
+/// The hook state of a Lua state, kept in the Extra
+#[derive(Default)]
+pub struct HookState {
+    /// The hook that was installed when ours was, and its mask and count
+    prev: Option<(Hook, i32, i32)>,
+    budget: Option<BudgetState>,
+    interrupt: Option<Arc<AtomicBool>>,
+    /// The closure installed with set_hook()
+    closure: Option<HookFn>,
+    /// The closure while it runs, moved out of `closure` so that replacing
+    /// it does not drop the running closure
+    running: Option<HookFn>,
+    /// True if the closure was replaced or removed while it ran
+    replaced: bool
+}
+
+impl HookState {
+    fn replace_closure(&mut self, f: Option<HookFn>) {
+        self.closure = f;
+        self.replaced = self.running.is_some();
+    }
+
+    /// Puts the closure that ran back in place, or drops it if it was
+    /// replaced while it ran.
+    fn restore_closure(&mut self) {
+        if let Some(f) = self.running.take() {
+            if !mem::replace(&mut self.replaced, false) {
+                self.closure = Some(f);
+            }
+        }
+    }
+}
+
+// Instructions between checks of the budget
+const TICK: i32 = 1000;
+
+// The addresses of these statics are the error objects raised when the budget
+// runs out or on an interrupt, as light userdata
+static BUDGET_SENTINEL: u8 = 0;
+static INTERRUPT_SENTINEL: u8 = 0;
+
+/// Returns true if the value at `idx` is the error object raised when a
+/// budget runs out.
+pub unsafe fn is_budget_sentinel(L: &mut RawState, idx: i32) -> bool {
+    L.islightuserdata(idx) && L.touserdata(idx) == &BUDGET_SENTINEL as *const u8 as *mut libc::c_void
+}
+
+/// Returns true if the value at `idx` is the error object raised on an
+/// interrupt. If so, the interrupt is cleared, as it has been reported.
+pub unsafe fn take_interrupt_sentinel(L: &mut RawState, idx: i32) -> bool {
+    if L.islightuserdata(idx) &&
+       L.touserdata(idx) == &INTERRUPT_SENTINEL as *const u8 as *mut libc::c_void {
+        if let Some(ref flag) = (*extra(L)).hooks.interrupt {
+            flag.store(false, Ordering::SeqCst);
+        }
+        true
+    } else {
+        false
+    }
+}
+
+impl State {
+    /// Sets a Rust closure as the debugging hook, like sethook().
+    ///
+    /// `f` receives the state and the event; `mask` and `count` are as for
+    /// sethook(). The closure replaces any previous hook, and is dropped when
+    /// it is replaced, when remove_hook() is called, or when the state is
+    /// closed. It may replace or remove itself while it runs. A panic in the
+    /// closure is raised as a Lua error.
+    ///
+    /// Hooks are not called while a hook runs, so the closure is never
+    /// re-entered.
+    ///
+    /// The hook coexists with pcall_budget() and interrupt_handle(). Setting a
+    /// hook with sethook() while either is active disables them.
+    pub fn set_hook<F>(&mut self, mask: i32, count: i32, f: F)
+                      where F: FnMut(&mut ExternState, &HookEvent) + 'static {
+        #![inline(always)]
+        unsafe { self.as_extern().set_hook(mask, count, f) }
+    }
+
+    /// Removes the hook set with set_hook() or sethook(), dropping its
+    /// closure. Budgets and interrupts keep working.
+    pub fn remove_hook(&mut self) {
+        #![inline(always)]
+        unsafe { self.as_extern().remove_hook() }
+    }
+
+    /// Calls a function in protected mode like pcall_(), but stops it with an
+    /// error of kind BudgetExceeded once it has used up `budget`.
+    ///
+    /// The budget is checked from a count hook every 1000 VM instructions,
+    /// or sooner when fewer instructions remain, so time spent inside a
+    /// single C function is not interrupted. Scripts cannot catch the error
+    /// for good: once the budget is exceeded, the error is raised again at
+    /// every instruction.
+    ///
+    /// A hook that was already installed keeps receiving its events during
+    /// the call (count events at the budget's rate), and is restored
+    /// afterwards. A nested pcall_budget() replaces the outer budget for its
+    /// duration.
+    pub fn pcall_budget(&mut self, nargs: i32, nresults: i32, budget: Budget) -> Result<(),Error> {
+        #![inline(always)]
+        unsafe { self.as_extern().pcall_budget(nargs, nresults, budget) }
+    }
+
+    /// Returns a handle that can interrupt the Lua code running in this state
+    /// from another thread.
+    ///
+    /// The first call installs a count hook that checks for interrupts every
+    /// 1000 VM instructions; a hook that was already installed keeps
+    /// receiving its events. An interrupted script raises an error of kind
+    /// Interrupted at every instruction until the error is returned from
+    /// pcall_() or one of its variants, which clears the interrupt. An
+    /// interrupt requested while no Lua code runs stops the next script.
+    ///
+    /// Hooks are per thread, and a coroutine gets the hook of the thread that
+    /// creates it. Code running in a coroutine created before the first call
+    /// is therefore never interrupted.
+    pub fn interrupt_handle(&mut self) -> InterruptHandle {
+        #![inline(always)]
+        unsafe { self.as_extern().interrupt_handle() }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn set_hook<F>(&mut self, mask: i32, count: i32, f: F)
+                             where F: FnMut(&mut ExternState, &HookEvent) + 'static {
+        self.as_raw().set_hook(mask, count, f)
+    }
+
+    pub unsafe fn remove_hook(&mut self) {
+        self.as_raw().remove_hook()
+    }
+
+    pub unsafe fn pcall_budget(&mut self, nargs: i32, nresults: i32, budget: Budget)
+                              -> Result<(),Error> {
+        luaassert!(self, self.gettop() > nargs, "pcall_budget: stack underflow");
+        self.as_raw().pcall_budget(nargs, nresults, budget)
+    }
+
+    pub unsafe fn interrupt_handle(&mut self) -> InterruptHandle {
+        self.as_raw().interrupt_handle()
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn set_hook<F>(&mut self, mask: i32, count: i32, f: F)
+                             where F: FnMut(&mut ExternState, &HookEvent) + 'static {
+        let hooks = &mut (*extra(self)).hooks as *mut HookState;
+        (*hooks).replace_closure(Some(Box::new(f)));
+        self.install_hook(hooks, closure_hook, mask, count);
+    }
+
+    pub unsafe fn remove_hook(&mut self) {
+        let hooks = &mut (*extra(self)).hooks as *mut HookState;
+        (*hooks).replace_closure(None);
+        self.install_hook(hooks, closure_hook, 0, 0);
+    }
+
+    pub unsafe fn pcall_budget(&mut self, nargs: i32, nresults: i32, budget: Budget)
+                              -> Result<(),Error> {
+        let hooks = &mut (*extra(self)).hooks as *mut HookState;
+        let outer = (*hooks).budget.take();
+        (*hooks).budget = Some(BudgetState{
+            remaining: budget.instructions,
+            deadline: budget.time.map(|t| Instant::now() + t)
+        });
+        self.update_hook(hooks);
+
+        let res = self.pcall_(nargs, nresults, false);
+
+        (*hooks).budget = outer;
+        self.update_hook(hooks);
+        res
+    }
+
+    pub unsafe fn interrupt_handle(&mut self) -> InterruptHandle {
+        let hooks = &mut (*extra(self)).hooks as *mut HookState;
+        let flag = (*hooks).interrupt.get_or_insert_with(|| Arc::new(AtomicBool::new(false))).clone();
+        self.update_hook(hooks);
+        InterruptHandle{ flag: flag }
+    }
+
+    /// Installs the user hook `f`, behind dispatch_hook if that is installed.
+    unsafe fn install_hook(&mut self, hooks: *mut HookState, f: Hook, mask: i32, count: i32) {
+        let installed = self.gethookmask() != 0 && self.gethook() as usize == dispatch_hook as Hook as usize;
+        if installed {
+            (*hooks).prev = if mask != 0 { Some((f, mask, count)) } else { None };
+            self.update_hook(hooks);
+        } else {
+            self.sethook(f, mask, count);
+        }
+    }
+
+    /// Installs or removes dispatch_hook as needed by the current budget and
+    /// interrupt, saving or restoring the hook it replaces.
+    unsafe fn update_hook(&mut self, hooks: *mut HookState) {
+        let mask = self.gethookmask();
+        let installed = mask != 0 && self.gethook() as usize == dispatch_hook as Hook as usize;
+        if !installed {
+            (*hooks).prev = if mask != 0 {
+                Some((self.gethook(), mask, self.gethookcount()))
+            } else {
+                None
+            };
+        }
+        if (*hooks).budget.is_none() && (*hooks).interrupt.is_none() {
+            match (*hooks).prev.take() {
+                Some((f, mask, count)) => self.sethook(f, mask, count),
+                None => self.sethook(dispatch_hook, 0, 0)
+            }
+            return;
+        }
+        let prevmask = (*hooks).prev.map_or(0, |(_, mask, _)| mask);
+        let count = match (*hooks).budget {
+            Some(BudgetState{ remaining: Some(n), .. }) if n < TICK as u64 => n as i32,
+            _ => TICK
+        };
+        // a count of 0 would disable the count events
+        self.sethook(dispatch_hook, prevmask | MASKCOUNT, if count > 0 { count } else { 1 });
+    }
+}
+
+unsafe extern "C" fn dispatch_hook(L: *mut raw::lua_State, ar: *mut raw::lua_Debug) {
+    let mut state = RawState::from_lua_State(L);
+    let hooks = &mut (*extra(&mut state)).hooks as *mut HookState;
+    if let Some((f, mask, _)) = (*hooks).prev {
+        let bit = match (*ar).event {
+            raw::LUA_HOOKTAILRET => MASKRET,
+            event => 1 << event
+        };
+        if mask & bit != 0 {
+            f(L, ar);
+        }
+    }
+    if (*ar).event != raw::LUA_HOOKCOUNT {
+        return;
+    }
+    let exceeded = match (*hooks).budget {
+        Some(ref mut budget) => {
+            let used = state.gethookcount() as u64;
+            let out_of_instructions = match budget.remaining {
+                Some(ref mut n) => {
+                    *n = n.saturating_sub(used);
+                    *n == 0
+                }
+                None => false
+            };
+            out_of_instructions || budget.deadline.map_or(false, |d| Instant::now() >= d)
+        }
+        None => false
+    };
+    if exceeded {
+        // keep failing at every instruction, in case a script catches the error
+        if let Some(ref mut budget) = (*hooks).budget {
+            budget.remaining = Some(0);
+        }
+        raise_sentinel(&mut state, &BUDGET_SENTINEL);
+    }
+    let interrupted = (*hooks).interrupt.as_ref().map_or(false, |f| f.load(Ordering::SeqCst));
+    if interrupted {
+        raise_sentinel(&mut state, &INTERRUPT_SENTINEL);
+    }
+    state.update_hook(hooks);
+}
+
+/// Calls the closure installed with set_hook().
+unsafe extern "C" fn closure_hook(L: *mut raw::lua_State, ar: *mut raw::lua_Debug) {
+    let mut state = RawState::from_lua_State(L);
+    let hooks = &mut (*extra(&mut state)).hooks as *mut HookState;
+    let event = match DebugEvent::from_event((*ar).event) {
+        Some(event) => event,
+        None => return
+    };
+    // the closure is still out of place if it raised a Lua error last time
+    (*hooks).restore_closure();
+    let f = match (*hooks).closure.take() {
+        Some(f) => (*hooks).running.insert(f) as *mut HookFn,
+        None => return
+    };
+    let ev = HookEvent{ event: event, ar: ar };
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        (*f)(&mut ExternState::from_lua_State(L), &ev)
+    }));
+    (*hooks).restore_closure();
+    if let Err(payload) = res {
+        push_panic_message(L, payload);
+        raw::lua_error(L);
+    }
+}
+
+/// Raises `sentinel` as the error object, and makes the hook run at every
+/// instruction so a script that catches the error keeps failing.
+unsafe fn raise_sentinel(L: &mut RawState, sentinel: &'static u8) -> ! {
+    let mask = L.gethookmask();
+    L.sethook(dispatch_hook, mask, 1);
+    L.pushlightuserdata(sentinel as *const u8 as *mut libc::c_void);
+    raw::lua_error(L.get_lua_State());
+    unreachable!()
+}
@@ -0,0 +1,140 @@
+//! Iterators over Lua tables
+
+use std::marker;
+
+use {State, ExternState, RawState, FromLua, FromLuaError};
+use raw;
+
+/// Iterator over the key/value pairs of a table, created by pairs().
+///
+/// The iterator borrows the state, and keeps the traversal key on the stack
+/// while it is in progress. The key is popped when the iteration finishes or
+/// the iterator is dropped, so the stack is balanced even on an early break.
+/// Keys and values are converted with FromLua, which never modifies them.
+pub struct Pairs<'a, K, V> {
+    L: *mut raw::lua_State,
+    idx: i32,
+    started: bool,
+    done: bool,
+    _marker: marker::PhantomData<(&'a mut State, K, V)>
+}
+
+impl<'a, K: FromLua, V: FromLua> Iterator for Pairs<'a, K, V> {
+    type Item = Result<(K, V), FromLuaError>;
+
+    fn next(&mut self) -> Option<Result<(K, V), FromLuaError>> {
+        if self.done {
+            return None;
+        }
+        unsafe {
+            let mut L = RawState::from_lua_State(self.L);
+            if !self.started {
+                L.pushnil();
+                self.started = true;
+            }
+            if !L.next(self.idx) {
+                self.done = true;
+                return None;
+            }
+            let item = K::from_lua(&mut L, -2).and_then(|k| V::from_lua(&mut L, -1).map(|v| (k, v)));
+            // keep the key for the next call
+            L.pop(1);
+            Some(item)
+        }
+    }
+}
+
+impl<'a, K, V> Drop for Pairs<'a, K, V> {
+    fn drop(&mut self) {
+        if self.started && !self.done {
+            unsafe { RawState::from_lua_State(self.L).pop(1) }
+        }
+    }
+}
+
+/// Iterator over the array part of a table, created by ipairs().
+///
+/// It yields the values at t[1], t[2], ..., up to the first nil, reading them
+/// with rawgeti(). The stack is left unchanged between calls.
+pub struct IPairs<'a, V> {
+    L: *mut raw::lua_State,
+    idx: i32,
+    i: i32,
+    done: bool,
+    _marker: marker::PhantomData<(&'a mut State, V)>
+}
+
+impl<'a, V: FromLua> Iterator for IPairs<'a, V> {
+    type Item = Result<V, FromLuaError>;
+
+    fn next(&mut self) -> Option<Result<V, FromLuaError>> {
+        if self.done {
+            return None;
+        }
+        unsafe {
+            let mut L = RawState::from_lua_State(self.L);
+            self.i += 1;
+            L.rawgeti(self.idx, self.i);
+            if L.isnil(-1) {
+                L.pop(1);
+                self.done = true;
+                return None;
+            }
+            let item = V::from_lua(&mut L, -1);
+            L.pop(1);
+            Some(item)
+        }
+    }
+}
+
+impl State {
+    /// Returns an iterator over the key/value pairs of the table at the given
+    /// valid index, converted to `K` and `V`. A pair that cannot be converted
+    /// is yielded as an Err, and the traversal continues.
+    ///
+    /// Fails the task if the value at `idx` is not a table.
+    pub fn pairs<'a, K: FromLua, V: FromLua>(&'a mut self, idx: i32) -> Pairs<'a, K, V> {
+        #![inline(always)]
+        unsafe { self.as_extern().pairs(idx) }
+    }
+
+    /// Returns an iterator over the values t[1], t[2], ... of the table at the
+    /// given valid index, up to the first nil, converted to `V`.
+    ///
+    /// Fails the task if the value at `idx` is not a table.
+    pub fn ipairs<'a, V: FromLua>(&'a mut self, idx: i32) -> IPairs<'a, V> {
+        #![inline(always)]
+        unsafe { self.as_extern().ipairs(idx) }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn pairs<'a, K: FromLua, V: FromLua>(&'a mut self, idx: i32) -> Pairs<'a, K, V> {
+        self.check_valid(idx, true);
+        luaassert!(self, self.istable(idx), "pairs: table expected");
+        // the key and value, plus a slot for converting numbers
+        self.checkstack_(3);
+        self.as_raw().pairs(idx)
+    }
+
+    pub unsafe fn ipairs<'a, V: FromLua>(&'a mut self, idx: i32) -> IPairs<'a, V> {
+        self.check_valid(idx, true);
+        luaassert!(self, self.istable(idx), "ipairs: table expected");
+        self.checkstack_(2);
+        self.as_raw().ipairs(idx)
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn pairs<'a, K: FromLua, V: FromLua>(&'a mut self, idx: i32) -> Pairs<'a, K, V> {
+        Pairs{ L: self.L, idx: self.absindex(idx), started: false, done: false,
+               _marker: marker::PhantomData }
+    }
+
+    pub unsafe fn ipairs<'a, V: FromLua>(&'a mut self, idx: i32) -> IPairs<'a, V> {
+        IPairs{ L: self.L, idx: self.absindex(idx), i: 0, done: false,
+                _marker: marker::PhantomData }
+    }
+}
@@ -0,0 +1,269 @@
+//! A minimal JSON value, parser and serializer, for the DAP server
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A JSON value. Objects keep the order of their members.
+#[derive(Clone,PartialEq,Debug)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    /// Parses a JSON text. Returns None if it is not valid JSON, or if its
+    /// arrays and objects are nested more than MAX_DEPTH deep.
+    pub fn parse(s: &str) -> Option<Json> {
+        let mut chars = s.chars().peekable();
+        let value = parse_value(&mut chars, MAX_DEPTH)?;
+        skip_whitespace(&mut chars);
+        if chars.next().is_some() { None } else { Some(value) }
+    }
+
+    /// Returns an object with the given members.
+    pub fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(members.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    /// Returns the member `key` of an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref members) => members.iter().find(|m| m.0 == key).map(|m| &m.1),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Json::Number(n) if n.fract() == 0.0 => Some(n as i64),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match *self {
+            Json::Array(ref items) => Some(items),
+            _ => None
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Json {
+    fn from(s: &'a str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Json {
+        Json::Array(items)
+    }
+}
+
+/// Formats the value as compact JSON.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if !n.is_finite() => f.write_str("null"),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => write_string(f, s),
+            Json::Array(ref items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(ref members) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?
+        }
+    }
+    f.write_str("\"")
+}
+
+// Deepest nesting of arrays and objects accepted, so that parsing cannot
+// overflow the stack
+const MAX_DEPTH: usize = 128;
+
+type Input<'a> = Peekable<Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Input) {
+    while chars.peek().map_or(false, |c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn expect_word(chars: &mut Input, word: &str, value: Json) -> Option<Json> {
+    for c in word.chars() {
+        if chars.next() != Some(c) {
+            return None;
+        }
+    }
+    Some(value)
+}
+
+/// Parses a value, in which at most `depth` arrays and objects may be nested.
+fn parse_value(chars: &mut Input, depth: usize) -> Option<Json> {
+    skip_whitespace(chars);
+    match *chars.peek()? {
+        '[' | '{' if depth == 0 => None,
+        'n' => expect_word(chars, "null", Json::Null),
+        't' => expect_word(chars, "true", Json::Bool(true)),
+        'f' => expect_word(chars, "false", Json::Bool(false)),
+        '"' => parse_string(chars).map(Json::String),
+        '[' => {
+            chars.next();
+            let mut items = Vec::new();
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Some(Json::Array(items));
+            }
+            loop {
+                items.push(parse_value(chars, depth - 1)?);
+                skip_whitespace(chars);
+                match chars.next()? {
+                    ',' => (),
+                    ']' => return Some(Json::Array(items)),
+                    _ => return None
+                }
+            }
+        }
+        '{' => {
+            chars.next();
+            let mut members = Vec::new();
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Some(Json::Object(members));
+            }
+            loop {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+                skip_whitespace(chars);
+                if chars.next()? != ':' {
+                    return None;
+                }
+                members.push((key, parse_value(chars, depth - 1)?));
+                skip_whitespace(chars);
+                match chars.next()? {
+                    ',' => (),
+                    '}' => return Some(Json::Object(members)),
+                    _ => return None
+                }
+            }
+        }
+        _ => {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_digit() || "+-.eE".contains(c)) {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            number.parse().ok().map(Json::Number)
+        }
+    }
+}
+
+fn parse_string(chars: &mut Input) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => match chars.next()? {
+                'n' => s.push('\n'),
+                'r' => s.push('\r'),
+                't' => s.push('\t'),
+                'b' => s.push('\u{8}'),
+                'f' => s.push('\u{c}'),
+                'u' => {
+                    let mut code = parse_hex4(chars)?;
+                    // a surrogate pair
+                    if (0xD800..0xDC00).contains(&code) {
+                        if chars.next()? != '\\' || chars.next()? != 'u' {
+                            return None;
+                        }
+                        let low = parse_hex4(chars)?;
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low.checked_sub(0xDC00)? & 0x3FF);
+                    }
+                    s.push(::std::char::from_u32(code).unwrap_or('\u{FFFD}'));
+                }
+                c => s.push(c)
+            },
+            c => s.push(c)
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut Input) -> Option<u32> {
+    let mut code = 0;
+    for _ in 0..4 {
+        code = code * 16 + chars.next()?.to_digit(16)?;
+    }
+    Some(code)
+}
@@ -57,12 +57,6 @@ pub mod aux;
 #[allow(missing_docs)]
 pub mod lib;
 
-#[path="macro.rs"]
-mod macros;
-
-#[cfg(test)]
-mod tests;
-
 macro_rules! luaassert{
     ($state:expr, $cond:expr, $msg:expr) => {
         if !$cond {
@@ -77,6 +71,78 @@ macro_rules! luaassert{
     }
 }
 
+#[path="macro.rs"]
+#[macro_use]
+mod macros;
+#[doc(hidden)]
+pub use macros::extern_call as __extern_call;
+#[doc(hidden)]
+pub use macros::extern_call_result as __extern_call_result;
+pub use macros::{ToLuaError, ErrorValue};
+
+mod convert;
+pub use convert::{ToLua, FromLua, FromLuaError};
+
+mod closure;
+
+mod preload;
+
+mod searcher;
+pub use searcher::{ModuleSource, Chunk, MemorySource, DirSource};
+
+mod error;
+pub use error::{Error, ErrorKind};
+
+mod extra;
+
+mod backtrace;
+pub use backtrace::{Frame, Traceback};
+
+mod luaref;
+pub use luaref::LuaRef;
+
+mod userdata;
+pub use userdata::{UserData, UserDataMethods, Method};
+
+mod iter;
+pub use iter::{Pairs, IPairs};
+
+mod stream;
+
+mod memory;
+pub use memory::{StateBuilder, MemoryStats};
+
+mod hooks;
+pub use hooks::{Budget, InterruptHandle, HookEvent};
+
+mod sandbox;
+pub use sandbox::Sandbox;
+
+mod thread;
+pub use thread::{Thread, Resumed, ThreadStatus};
+
+mod executor;
+pub use executor::Executor;
+
+mod profiler;
+pub use profiler::{Profiler, Profile, FunctionStats};
+
+mod coverage;
+pub use coverage::{Coverage, CoverageReport};
+
+mod debugger;
+pub use debugger::{Debugger, DebugHandler, Session, PauseReason, Resume, Variable, PauseHandle};
+
+mod json;
+
+mod dap;
+pub use dap::DapServer;
+
+pub mod repl;
+
+#[cfg(test)]
+mod tests;
+
 /// Lua value types
 #[derive(Clone,Copy,PartialEq,Eq,Debug)]
 pub enum Type {
@@ -253,7 +319,7 @@ impl Drop for State {
     fn drop(&mut self) {
         if !self.L.is_null() {
             unsafe {
-                raw::lua_close(self.L);
+                memory::close(self.L);
             }
         }
     }
@@ -292,30 +358,11 @@ impl State {
     }
 
     /// Returns a new State, or None if memory cannot be allocated for the state
+    ///
+    /// See StateBuilder for more options.
     pub fn new_opt() -> Option<State> {
-        return unsafe {
-            let L = raw::lua_newstate(alloc, ptr::null_mut());
-            if !L.is_null() {
-                raw::lua_atpanic(L, panic);
-                Some(State{ L: L, _stackspace: MINSTACK, _marker: marker::PhantomData })
-            } else {
-                None
-            }
-        };
-
-        unsafe extern "C" fn alloc(_ud: *mut libc::c_void, ptr: *mut libc::c_void,
-                                   _osize: libc::size_t, nsize: libc::size_t) -> *mut libc::c_void {
-            if nsize == 0 {
-                libc::free(ptr);
-                ptr::null_mut()
-            } else {
-                libc::realloc(ptr, nsize)
-            }
-        }
-        unsafe extern "C" fn panic(L: *mut raw::lua_State) -> c_int {
-            let s = RawState::from_lua_State(L).describe_(-1, false);
-            panic!("unprotected error in call to Lua API ({})", s);
-        }
+        #![inline]
+        StateBuilder::new().build()
     }
 }
 
@@ -973,7 +1020,7 @@ impl State {
     /// While traversing a table, do not call tostring() or tobytes() directly
     /// on a key, unless you know that the key is actually a string. Recall
     /// that tostring() changes the value at the given index; this confuses the
-    /// next call to next().
+    /// next call to next(). See pairs() for an iterator that avoids this.
     pub fn next(&mut self, idx: i32) -> bool {
         #![inline(always)]
         unsafe { self.as_extern().next(idx) }
@@ -1437,7 +1484,7 @@ impl<'l> ExternState<'l> {
         luaassert!(self, nargs >= 0, "call: invalid nargs");
         luaassert!(self, nresults == MULTRET || nresults >= 0, "call: invalid nresults");
         luaassert!(self, self.gettop() > nargs, "call: stack underflow");
-        if nresults > nargs + 1 { self.checkstack_(nargs - nresults - 1) }
+        if nresults > nargs + 1 { self.checkstack_(nresults - nargs - 1) }
         self.as_raw().call(nargs, nresults)
     }
 
@@ -1449,7 +1496,7 @@ impl<'l> ExternState<'l> {
         if errfunc != 0 {
             self.check_valid(errfunc, false)
         }
-        if nresults > nargs + 1 { self.checkstack_(nargs - nresults - 1) }
+        if nresults > nargs + 1 { self.checkstack_(nresults - nargs - 1) }
         self.as_raw().pcall(nargs, nresults, errfunc)
     }
 
@@ -2489,6 +2536,8 @@ impl State {
     /// If the object at the top of the stack is nil, ref_() returns the
     /// constant RefNil. The constant NoRef is guaranteed to be different from
     /// any reference returned by ref_().
+    ///
+    /// See newref() for an owned reference that is released automatically.
     pub fn ref_(&mut self, t: i32) -> i32 {
         #![inline(always)]
         unsafe { self.as_extern().ref_(t) }
@@ -2775,7 +2824,7 @@ impl<'l> RawState<'l> {
         }
         l_.push(aux::raw::luaL_Reg{ name: ptr::null(), func: None });
         let libcstr = libname.map(|s| CString::new(s).unwrap());
-        let libname_ = libcstr.map_or(ptr::null(), |cstr| cstr.as_ptr());
+        let libname_ = libcstr.as_ref().map_or(ptr::null(), |cstr| cstr.as_ptr());
         aux::raw::luaL_register(self.L, libname_, l_.as_ptr())
     }
 
@@ -3102,7 +3151,7 @@ impl<'a> Buffer<'a> {
 
 /* Debug API */
 /// Event codes
-#[derive(Copy,Clone)]
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
 pub enum DebugEvent {
     /// The call hook is called when the interpreter calls a function. The hook is called
     /// just after Lua enters the new function, before the function gets its arguments.
@@ -3435,6 +3484,19 @@ impl<'l> RawState<'l> {
     }
 }
 
+impl<'l> RawState<'l> {
+    /// Converts a relative stack index into an absolute one, so it stays
+    /// valid while values are pushed. Pseudo-indices are returned unchanged.
+    fn absindex(&mut self, idx: i32) -> i32 {
+        #![inline]
+        if idx < 0 && idx > REGISTRYINDEX {
+            self.gettop() + idx + 1
+        } else {
+            idx
+        }
+    }
+}
+
 unsafe fn c_str_to_bytes<'a>(cstr: *const libc::c_char) -> Option<&'a [u8]> {
     #![inline]
     if cstr.is_null() {
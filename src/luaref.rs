@@ -0,0 +1,102 @@
+//! Owned registry references
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use {State, ExternState, RawState, REGISTRYINDEX, REFNIL};
+use extra::extra;
+
+/// An owned reference to a Lua value pinned in the registry.
+///
+/// The value is kept alive until the LuaRef is dropped. Dropping it only
+/// queues the reference for release, since no state is at hand; the state
+/// unrefs queued references the next time it creates or pushes a LuaRef.
+/// A LuaRef may therefore be dropped on any thread, and may outlive its state.
+pub struct LuaRef {
+    r: i32,
+    queue: Arc<Mutex<Vec<i32>>>
+}
+
+impl LuaRef {
+    /// Returns true if the reference refers to nil.
+    pub fn is_nil(&self) -> bool {
+        self.r == REFNIL
+    }
+}
+
+impl Drop for LuaRef {
+    fn drop(&mut self) {
+        if self.r != REFNIL {
+            if let Ok(mut queue) = self.queue.lock() {
+                queue.push(self.r);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for LuaRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LuaRef({})", self.r)
+    }
+}
+
+impl State {
+    /// Pops the value at the top of the stack and returns an owned reference
+    /// to it. See LuaRef.
+    pub fn newref(&mut self) -> LuaRef {
+        #![inline(always)]
+        unsafe { self.as_extern().newref() }
+    }
+
+    /// Pushes the value referred to by `r` onto the stack.
+    ///
+    /// Fails the task if `r` was created by a different state.
+    pub fn pushref(&mut self, r: &LuaRef) {
+        #![inline(always)]
+        unsafe { self.as_extern().pushref(r) }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn newref(&mut self) -> LuaRef {
+        luaassert!(self, self.gettop() >= 1, "newref: stack underflow");
+        self.as_raw().newref()
+    }
+
+    pub unsafe fn pushref(&mut self, r: &LuaRef) {
+        self.checkstack_(1);
+        let queue = (*extra(self.as_raw())).refs.clone();
+        luaassert!(self, Arc::ptr_eq(&queue, &r.queue), "pushref: reference from a different state");
+        self.as_raw().pushref(r)
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn newref(&mut self) -> LuaRef {
+        let queue = self.release_refs();
+        // luaL_ref internally uses 1 stack slot
+        self.checkstack_(1);
+        LuaRef{ r: self.ref_(REGISTRYINDEX), queue: queue }
+    }
+
+    pub unsafe fn pushref(&mut self, r: &LuaRef) {
+        self.release_refs();
+        self.rawgeti(REGISTRYINDEX, r.r);
+    }
+
+    /// Unrefs every reference queued by dropped LuaRefs, and returns the
+    /// queue.
+    unsafe fn release_refs(&mut self) -> Arc<Mutex<Vec<i32>>> {
+        let queue = (*extra(self)).refs.clone();
+        let released = match queue.lock() {
+            Ok(mut refs) => refs.split_off(0),
+            Err(_) => Vec::new()
+        };
+        for r in released {
+            self.unref(REGISTRYINDEX, r);
+        }
+        queue
+    }
+}
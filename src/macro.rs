@@ -1,29 +1,188 @@
-/// Macro for defining Lua CFunctions
+use libc::c_int;
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use {ExternState, RawState, ToLua};
+use raw;
+use convert::push_one;
 
+/// Macro for defining Lua CFunctions
+///
+/// A panic that unwinds out of the body is caught and raised as a Lua error
+/// with the panic message, since unwinding through Lua's C frames is not
+/// allowed.
+///
+/// A function may also be declared as returning `Result<i32, E>` where `E`
+/// implements `ToLuaError` (e.g. any `Display` type). An `Err` is raised as a
+/// Lua error only after the body has returned, so every Rust value it owned
+/// has already been dropped:
+///
+///   lua_extern! {
+///       unsafe fn parse(L: &mut lua::ExternState) -> Result<i32, std::num::ParseIntError> {
+///           let n: i64 = L.checkstring(1).unwrap_or("").parse()?;
+///           L.pushnumber(n as f64);
+///           Ok(1)
+///       }
+///   }
 #[macro_export]
 macro_rules! lua_extern {
-    ($(unsafe fn $name:ident($arg:ident: &mut $typ:ty) -> i32 $code:block)+) => (
-        $(
-            unsafe extern "C" fn $name($arg: *mut ::lua::raw::lua_State) -> ::libc::c_int {
-                let mut $arg = ::lua::ExternState::from_lua_State($arg);
-                return inner(&mut $arg) as ::libc::c_int;
-
-                unsafe fn inner($arg: &mut $typ) -> i32 $code
-            }
-        )+
+    ($($t:tt)+) => (
+        $crate::__lua_extern!{ () $($t)+ }
     )
 }
 
+/// Variant of lua_extern!() that marks the generated functions as public.
 #[macro_export]
 macro_rules! lua_extern_pub {
-    ($(unsafe fn $name:ident($arg:ident: &mut $typ:ty) -> i32 $code:block)+) => (
-        $(
-            pub unsafe extern "C" fn $name($arg: *mut ::lua::raw::lua_State) -> ::libc::c_int {
-                let mut $arg = ::lua::ExternState::from_lua_State($arg);
-                return inner(&mut $arg) as ::libc::c_int;
+    ($($t:tt)+) => (
+        $crate::__lua_extern!{ (pub) $($t)+ }
+    )
+}
 
-                unsafe fn inner($arg: &mut $typ) -> i32 $code
+/// Macro for defining the entry point of a Lua C module
+///
+/// This generates a public, unmangled luaopen_* function that opens the
+/// library with registerlib(), so that a `cdylib` crate exporting it can be
+/// loaded with require() by the standalone `lua` interpreter. Each function
+/// is registered under its Rust name, and must be a CFunction, e.g. one
+/// defined with lua_extern!(). The entry point itself catches panics like
+/// lua_extern!() does.
+///
+///   lua_module! {
+///       luaopen_geometry = "geometry" { area, perimeter }
+///   }
+///
+/// require() looks up the entry point by the module name, with '.' replaced
+/// by '_': module "a.b" needs luaopen_a_b. The module must use the Lua
+/// library of the interpreter that loads it, so link it against the shared
+/// Lua library rather than a static one.
+#[macro_export]
+macro_rules! lua_module {
+    ($open:ident = $name:tt { $($f:ident),* $(,)* }) => (
+        $crate::__lua_extern!{ (#[no_mangle] pub)
+            unsafe fn $open(L: &mut $crate::ExternState) -> i32 {
+                L.registerlib(Some($name), &[$((stringify!($f), $f as $crate::CFunction)),*]);
+                1
             }
-        )+
+        }
     )
 }
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __lua_extern {
+    (($($vis:tt)*)) => ();
+    (($($vis:tt)*) unsafe fn $name:ident($arg:ident: &mut $typ:ty) -> i32 $code:block
+     $($rest:tt)*) => (
+        $($vis)* unsafe extern "C" fn $name($arg: *mut $crate::raw::lua_State) -> ::libc::c_int {
+            let L = $arg;
+            return $crate::__extern_call(L, || {
+                let mut $arg = $crate::ExternState::from_lua_State(L);
+                inner(&mut $arg)
+            });
+
+            unsafe fn inner($arg: &mut $typ) -> i32 $code
+        }
+
+        $crate::__lua_extern!{ ($($vis)*) $($rest)* }
+    );
+    (($($vis:tt)*) unsafe fn $name:ident($arg:ident: &mut $typ:ty) -> Result<i32, $err:ty> $code:block
+     $($rest:tt)*) => (
+        $($vis)* unsafe extern "C" fn $name($arg: *mut $crate::raw::lua_State) -> ::libc::c_int {
+            let L = $arg;
+            return $crate::__extern_call_result(L, || {
+                let mut $arg = $crate::ExternState::from_lua_State(L);
+                inner(&mut $arg)
+            });
+
+            unsafe fn inner($arg: &mut $typ) -> Result<i32, $err> $code
+        }
+
+        $crate::__lua_extern!{ ($($vis)*) $($rest)* }
+    )
+}
+
+/// Error types that can be raised as Lua errors from a `Result`-returning
+/// lua_extern!() function.
+///
+/// Every `Display` type is raised as a string message, prefixed with the
+/// position of the caller like errorstr(). Wrap a value in `ErrorValue` to
+/// raise it unchanged instead.
+pub trait ToLuaError {
+    /// Pushes the error object onto the stack, consuming the error.
+    unsafe fn push_error(self, L: &mut ExternState);
+}
+
+impl<T: fmt::Display> ToLuaError for T {
+    unsafe fn push_error(self, L: &mut ExternState) {
+        let msg = self.to_string();
+        L.checkstack_(2);
+        L.where_(1);
+        L.pushstring(&msg);
+        L.concat(2);
+    }
+}
+
+/// An error that is raised as the wrapped Lua value, without any position
+/// information. This allows raising tables or other non-string error objects.
+pub struct ErrorValue<T: ToLua>(pub T);
+
+impl<T: ToLua> ToLuaError for ErrorValue<T> {
+    unsafe fn push_error(self, L: &mut ExternState) {
+        L.checkstack_(1);
+        push_one(L.as_raw(), &self.0);
+    }
+}
+
+/// Runs the body of a CFunction, converting a panic into a Lua error.
+///
+/// This is used by lua_extern!() and is not meant to be called directly.
+#[doc(hidden)]
+pub unsafe fn extern_call<F: FnOnce() -> i32>(L: *mut raw::lua_State, f: F) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(n) => n as c_int,
+        Err(payload) => {
+            push_panic_message(L, payload);
+            // all Rust values are gone by now, so it's safe to longjmp
+            raw::lua_error(L)
+        }
+    }
+}
+
+/// Runs the body of a `Result`-returning CFunction, converting an `Err` or a
+/// panic into a Lua error.
+///
+/// This is used by lua_extern!() and is not meant to be called directly.
+#[doc(hidden)]
+pub unsafe fn extern_call_result<F, E>(L: *mut raw::lua_State, f: F) -> c_int
+                                      where F: FnOnce() -> Result<i32, E>, E: ToLuaError {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(n)) => n as c_int,
+        Ok(Err(err)) => {
+            err.push_error(&mut ExternState::from_lua_State(L));
+            raw::lua_error(L)
+        }
+        Err(payload) => {
+            push_panic_message(L, payload);
+            raw::lua_error(L)
+        }
+    }
+}
+
+/// Pushes an error message describing the panic payload. The payload is
+/// dropped before returning.
+pub unsafe fn push_panic_message(L: *mut raw::lua_State, payload: Box<dyn Any + Send>) {
+    let msg = if let Some(s) = payload.downcast_ref::<&'static str>() {
+        format!("rust panic: {}", s)
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        format!("rust panic: {}", s)
+    } else {
+        "rust panic".to_string()
+    };
+    let mut L = RawState::from_lua_State(L);
+    L.checkstack_(2);
+    L.where_(1);
+    L.pushstring(&msg);
+    L.concat(2);
+}
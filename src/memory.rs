@@ -0,0 +1,128 @@
+//! State construction with a counting, optionally limited allocator
+
+use libc;
+use std::{cmp, marker, ptr};
+
+use {State, RawState, Alloc, MINSTACK};
+use raw;
+
+/// Builder for a State with non-default options.
+///
+/// Every State built this way counts its memory use; see memory_stats().
+#[derive(Clone,Debug,Default)]
+pub struct StateBuilder {
+    memory_limit: Option<usize>
+}
+
+/// A snapshot of the memory use of a State
+#[derive(Copy,Clone,PartialEq,Eq,Debug,Default)]
+pub struct MemoryStats {
+    /// Bytes currently allocated
+    pub current: usize,
+    /// Highest value of `current` so far
+    pub peak: usize,
+    /// Number of blocks allocated so far
+    pub allocations: usize,
+    /// The memory limit, if any
+    pub limit: Option<usize>
+}
+
+/// The allocator state, passed to counting_alloc as `ud`
+struct AllocState {
+    limit: usize,
+    stats: MemoryStats
+}
+
+impl StateBuilder {
+    /// Returns a builder with the default options.
+    pub fn new() -> StateBuilder {
+        StateBuilder{ memory_limit: None }
+    }
+
+    /// Sets a hard limit on the bytes allocated by the state. An allocation
+    /// beyond the limit fails, which raises a memory error in Lua (ErrMem or
+    /// ErrorKind::Memory). The limit must leave room for the state itself,
+    /// or build() fails.
+    pub fn memory_limit(mut self, limit: usize) -> StateBuilder {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Returns a new State, or None if memory cannot be allocated for the
+    /// state.
+    pub fn build(self) -> Option<State> {
+        return unsafe {
+            let ud = Box::into_raw(Box::new(AllocState{
+                limit: self.memory_limit.unwrap_or(usize::MAX),
+                stats: MemoryStats{ limit: self.memory_limit, ..MemoryStats::default() }
+            }));
+            let L = raw::lua_newstate(counting_alloc, ud as *mut libc::c_void);
+            if !L.is_null() {
+                raw::lua_atpanic(L, panic);
+                Some(State{ L: L, _stackspace: MINSTACK, _marker: marker::PhantomData })
+            } else {
+                drop(Box::from_raw(ud));
+                None
+            }
+        };
+
+        unsafe extern "C" fn panic(L: *mut raw::lua_State) -> libc::c_int {
+            let s = RawState::from_lua_State(L).describe_(-1, false);
+            panic!("unprotected error in call to Lua API ({})", s);
+        }
+    }
+}
+
+impl State {
+    /// Returns the memory use of the state, or None if it does not use the
+    /// allocator installed by State::new() and StateBuilder.
+    pub fn memory_stats(&mut self) -> Option<MemoryStats> {
+        unsafe { alloc_state(self.L).map(|st| (*st).stats) }
+    }
+}
+
+/// Returns the AllocState of `L`, if it uses counting_alloc.
+unsafe fn alloc_state(L: *mut raw::lua_State) -> Option<*mut AllocState> {
+    let mut ud = ptr::null_mut();
+    let f = raw::lua_getallocf(L, &mut ud);
+    if f as usize == counting_alloc as Alloc as usize {
+        Some(ud as *mut AllocState)
+    } else {
+        None
+    }
+}
+
+/// Closes `L` and frees its allocator state.
+pub unsafe fn close(L: *mut raw::lua_State) {
+    let st = alloc_state(L);
+    raw::lua_close(L);
+    if let Some(st) = st {
+        drop(Box::from_raw(st));
+    }
+}
+
+unsafe extern "C" fn counting_alloc(ud: *mut libc::c_void, ptr: *mut libc::c_void,
+                                    osize: libc::size_t, nsize: libc::size_t) -> *mut libc::c_void {
+    let st = &mut *(ud as *mut AllocState);
+    // Lua passes osize == 0 when ptr is NULL
+    let osize = if ptr.is_null() { 0 } else { osize as usize };
+    let nsize = nsize as usize;
+    if nsize == 0 {
+        libc::free(ptr);
+        st.stats.current -= osize;
+        return ptr::null_mut();
+    }
+    // Lua assumes that shrinking a block never fails, so only growth is limited
+    if nsize > osize && st.stats.current - osize + nsize > st.limit {
+        return ptr::null_mut();
+    }
+    let p = libc::realloc(ptr, nsize);
+    if !p.is_null() {
+        st.stats.current = st.stats.current - osize + nsize;
+        st.stats.peak = cmp::max(st.stats.peak, st.stats.current);
+        if ptr.is_null() {
+            st.stats.allocations += 1;
+        }
+    }
+    p
+}
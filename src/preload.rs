@@ -0,0 +1,105 @@
+//! Lazily loaded Rust modules, through package.preload
+
+use {State, ExternState, RawState, CFunction};
+
+impl State {
+    /// Registers `opener` as the loader of module `name` in
+    /// `package.preload`, so that `require(name)` calls it the first time
+    /// the module is required.
+    ///
+    /// Like a luaopen_* function, the opener is called with the module name
+    /// as its argument, and returns the module, usually a table. The package
+    /// library must be open.
+    ///
+    /// Fails the task if `name` has interior NULs or if `package.preload` is
+    /// not a table.
+    pub fn preload(&mut self, name: &str, opener: CFunction) {
+        #![inline(always)]
+        unsafe { self.as_extern().preload(name, opener) }
+    }
+
+    /// Registers the Rust closure `f` as the loader of module `name` in
+    /// `package.preload`. See preload() and push_closure() for details.
+    pub fn preload_closure<F>(&mut self, name: &str, f: F)
+                             where F: FnMut(&mut ExternState) -> i32 + 'static {
+        #![inline(always)]
+        unsafe { self.as_extern().preload_closure(name, f) }
+    }
+
+    /// Registers module `name` in `package.preload` as a table of the
+    /// functions in the list `l`, created when the module is first required.
+    /// See preload() for details.
+    pub fn preloadlib(&mut self, name: &str, l: &[(&str,CFunction)]) {
+        #![inline(always)]
+        unsafe { self.as_extern().preloadlib(name, l) }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn preload(&mut self, name: &str, opener: CFunction) {
+        self.check_preload();
+        // the opener and a copy, and the package and preload tables
+        self.checkstack_(4);
+        self.as_raw().preload(name, opener)
+    }
+
+    pub unsafe fn preload_closure<F>(&mut self, name: &str, f: F)
+                                    where F: FnMut(&mut ExternState) -> i32 + 'static {
+        self.check_preload();
+        // the closure, and the package and preload tables
+        self.checkstack_(5);
+        self.as_raw().preload_closure(name, f)
+    }
+
+    pub unsafe fn preloadlib(&mut self, name: &str, l: &[(&str,CFunction)]) {
+        self.check_preload();
+        self.checkstack_(5);
+        self.as_raw().preloadlib(name, l)
+    }
+
+    /// Fails the task if `package.preload` is not a table.
+    unsafe fn check_preload(&mut self) {
+        self.checkstack_(2);
+        self.getglobal("package");
+        luaassert!(self, self.istable(-1), "preload: package library not open");
+        self.getfield(-1, "preload");
+        luaassert!(self, self.istable(-1), "preload: package.preload is not a table");
+        self.pop(2);
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn preload(&mut self, name: &str, opener: CFunction) {
+        self.pushcfunction(opener);
+        self.setpreload(name)
+    }
+
+    pub unsafe fn preload_closure<F>(&mut self, name: &str, f: F)
+                                    where F: FnMut(&mut ExternState) -> i32 + 'static {
+        self.push_closure(f);
+        self.setpreload(name)
+    }
+
+    pub unsafe fn preloadlib(&mut self, name: &str, l: &[(&str,CFunction)]) {
+        let l: Vec<(String, CFunction)> = l.iter().map(|&(name, f)| (name.to_string(), f)).collect();
+        self.preload_closure(name, move |L: &mut ExternState| {
+            L.createtable(0, l.len() as i32);
+            for (name, f) in &l {
+                L.pushcfunction(*f);
+                L.setfield(-2, name);
+            }
+            1
+        })
+    }
+
+    /// Pops the value on top of the stack into `package.preload[name]`.
+    unsafe fn setpreload(&mut self, name: &str) {
+        self.getglobal("package");
+        self.getfield(-1, "preload");
+        self.pushvalue(-3);
+        self.setfield(-2, name);
+        self.pop(3);
+    }
+}
@@ -0,0 +1,340 @@
+//! Instrumenting and sampling profiler built on set_hook()
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use {State, ExternState, HookEvent, DebugEvent, Frame, MASKCALL, MASKRET, MASKCOUNT};
+
+/// A profiler that records per-function call counts and times through call
+/// and return hooks, and samples the stack through a count hook.
+///
+/// The profiler uses the hook set with set_hook(), replacing any other hook
+/// while it runs. Coroutines created after start() share its hook, and their
+/// stacks are tracked separately.
+#[derive(Clone,Debug)]
+pub struct Profiler {
+    sample_interval: i32,
+    recorder: Rc<RefCell<Recorder>>
+}
+
+/// The statistics of one function
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct FunctionStats {
+    /// The name of the function, e.g. "update", or "?" if it was never
+    /// called by name
+    pub name: String,
+    /// The short source of the chunk that defined the function ("[C]" for C
+    /// functions)
+    pub source: String,
+    /// The line where the definition of the function starts (-1 for C
+    /// functions)
+    pub linedefined: i32,
+    /// "Lua", "C", or "main"
+    pub what: String,
+    /// The number of calls
+    pub calls: u64,
+    /// The time spent in the function, including the functions it called
+    pub inclusive: Duration,
+    /// The time spent in the function itself
+    pub exclusive: Duration
+}
+
+impl FunctionStats {
+    /// Returns a label identifying the function, e.g. "update
+    /// (game.lua:12)", as used in the exported formats.
+    pub fn label(&self) -> String {
+        label(&self.name, &self.source, self.linedefined, &self.what)
+    }
+}
+
+/// The data recorded by a Profiler between start() and stop()
+#[derive(Clone,Debug,Default)]
+pub struct Profile {
+    functions: Vec<FunctionStats>,
+    /// Caller and callee indexes into `functions`, call count and time
+    calls: Vec<(usize, usize, u64, Duration)>,
+    samples: BTreeMap<String, u64>
+}
+
+impl Profile {
+    /// Returns the statistics of every function called, most expensive
+    /// (by inclusive time) first.
+    pub fn functions(&self) -> &[FunctionStats] {
+        &self.functions
+    }
+
+    /// Returns the sampled stacks and how many samples hit each. A stack is
+    /// the labels of its functions, outermost first, joined by ';'.
+    pub fn samples(&self) -> &BTreeMap<String, u64> {
+        &self.samples
+    }
+
+    /// Writes the sampled stacks in the folded format of flamegraph.pl: one
+    /// line per stack, followed by its sample count.
+    pub fn write_folded<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for (stack, count) in &self.samples {
+            writeln!(w, "{} {}", stack, count)?;
+        }
+        w.flush()
+    }
+
+    /// Writes the call graph in the callgrind format, with costs in
+    /// microseconds, for use with tools such as KCachegrind.
+    pub fn write_callgrind<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "# callgrind format")?;
+        writeln!(w, "version: 1")?;
+        writeln!(w, "creator: rust-lua")?;
+        writeln!(w, "positions: line")?;
+        writeln!(w, "events: Microseconds")?;
+        for (i, f) in self.functions.iter().enumerate() {
+            writeln!(w)?;
+            writeln!(w, "fl={}", f.source)?;
+            writeln!(w, "fn={}", f.label())?;
+            writeln!(w, "{} {}", line(f), micros(f.exclusive))?;
+            for &(_, callee, calls, time) in self.calls.iter().filter(|c| c.0 == i) {
+                let callee = &self.functions[callee];
+                writeln!(w, "cfl={}", callee.source)?;
+                writeln!(w, "cfn={}", callee.label())?;
+                writeln!(w, "calls={} {}", calls, line(callee))?;
+                writeln!(w, "{} {}", line(f), micros(time))?;
+            }
+        }
+        w.flush()
+    }
+}
+
+#[derive(Debug,Default)]
+struct Recorder {
+    /// Function indexes by function address, source and line
+    index: HashMap<(usize, String, i32), usize>,
+    functions: Vec<FunctionStats>,
+    calls: HashMap<(usize, usize), (u64, Duration)>,
+    /// The active calls of each thread, by the address of its lua_State
+    stacks: HashMap<usize, Vec<Active>>,
+    samples: BTreeMap<String, u64>
+}
+
+#[derive(Debug)]
+struct Active {
+    func: usize,
+    start: Instant,
+    children: Duration
+}
+
+impl Profiler {
+    /// Returns a profiler that samples the stack every 1000 VM instructions.
+    pub fn new() -> Profiler {
+        Profiler{ sample_interval: 1000, recorder: Rc::new(RefCell::new(Recorder::default())) }
+    }
+
+    /// Sets the number of VM instructions between stack samples. An interval
+    /// of 0 disables sampling.
+    pub fn sample_interval(mut self, instructions: i32) -> Profiler {
+        self.sample_interval = instructions;
+        self
+    }
+
+    /// Starts recording the Lua code run by `L`, discarding anything recorded
+    /// before.
+    pub fn start(&self, L: &mut State) {
+        *self.recorder.borrow_mut() = Recorder::default();
+        let recorder = self.recorder.clone();
+        let mut mask = MASKCALL | MASKRET;
+        if self.sample_interval > 0 {
+            mask |= MASKCOUNT;
+        }
+        L.set_hook(mask, self.sample_interval, move |L, ev| unsafe {
+            let mut recorder = recorder.borrow_mut();
+            let thread = L.get_lua_State() as usize;
+            match ev.event() {
+                DebugEvent::HookCall => recorder.call(thread, function_id(L, ev), ev.frame(L)),
+                DebugEvent::HookRet => recorder.ret(thread, Some((function_id(L, ev), ev.frame(L)))),
+                DebugEvent::HookTailRet => recorder.ret(thread, None),
+                DebugEvent::HookCount => recorder.sample(L),
+                DebugEvent::HookLine => ()
+            }
+        });
+    }
+
+    /// Stops recording, removing the hook, and returns what was recorded.
+    /// Calls that are still running count as returning now.
+    pub fn stop(&self, L: &mut State) -> Profile {
+        L.remove_hook();
+        let mut recorder = mem::take(&mut *self.recorder.borrow_mut());
+        let now = Instant::now();
+        let threads: Vec<usize> = recorder.stacks.keys().cloned().collect();
+        for thread in threads {
+            while recorder.pop(thread, now) {}
+        }
+        recorder.into_profile()
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+impl Recorder {
+    fn function(&mut self, id: usize, frame: Frame) -> usize {
+        // the address of a collected function may be reused by another one
+        let key = (id, frame.short_src.clone(), frame.linedefined);
+        if let Some(&i) = self.index.get(&key) {
+            if self.functions[i].name == "?" {
+                if let Some(name) = frame.name {
+                    self.functions[i].name = name;
+                }
+            }
+            return i;
+        }
+        let i = self.functions.len();
+        self.functions.push(FunctionStats{
+            name: frame.name.unwrap_or_else(|| "?".to_string()),
+            source: frame.short_src,
+            linedefined: frame.linedefined,
+            what: frame.what,
+            calls: 0,
+            inclusive: Duration::from_secs(0),
+            exclusive: Duration::from_secs(0)
+        });
+        self.index.insert(key, i);
+        i
+    }
+
+    fn call(&mut self, thread: usize, id: usize, frame: Frame) {
+        let func = self.function(id, frame);
+        self.functions[func].calls += 1;
+        self.stacks.entry(thread).or_default().push(Active{
+            func: func,
+            start: Instant::now(),
+            children: Duration::from_secs(0)
+        });
+    }
+
+    /// Ends the call of the function `frame`, or of the innermost active
+    /// call if None. Calls above it were left by an error, and end as well.
+    fn ret(&mut self, thread: usize, frame: Option<(usize, Frame)>) {
+        let now = Instant::now();
+        let func = frame.map(|(id, f)| self.function(id, f));
+        let found = match (func, self.stacks.get(&thread)) {
+            (_, None) => false,
+            (None, Some(stack)) => !stack.is_empty(),
+            (Some(func), Some(stack)) => stack.iter().any(|a| a.func == func)
+        };
+        if !found {
+            return;
+        }
+        loop {
+            let top = self.stacks[&thread].last().map(|a| a.func);
+            self.pop(thread, now);
+            if func.is_none() || top == func {
+                break;
+            }
+        }
+    }
+
+    /// Ends the innermost active call of `thread`. Returns false if there is
+    /// none.
+    fn pop(&mut self, thread: usize, now: Instant) -> bool {
+        let stack = match self.stacks.get_mut(&thread) {
+            Some(stack) => stack,
+            None => return false
+        };
+        let active = match stack.pop() {
+            Some(active) => active,
+            None => return false
+        };
+        let elapsed = now.duration_since(active.start);
+        let caller = stack.last_mut().map(|parent| {
+            parent.children += elapsed;
+            parent.func
+        });
+        // the time of a recursive call is already part of the outer call
+        let recursive = stack.iter().any(|a| a.func == active.func);
+        let stats = &mut self.functions[active.func];
+        if !recursive {
+            stats.inclusive += elapsed;
+        }
+        stats.exclusive += elapsed.checked_sub(active.children).unwrap_or_default();
+        if let Some(caller) = caller {
+            let edge = self.calls.entry((caller, active.func)).or_insert((0, Duration::from_secs(0)));
+            edge.0 += 1;
+            edge.1 += elapsed;
+        }
+        true
+    }
+
+    unsafe fn sample(&mut self, L: &mut ExternState) {
+        let mut labels = Vec::new();
+        let mut level = 0;
+        L.checkstack_(1);
+        while let Some(mut ar) = L.getstack(level) {
+            L.getinfo("Snf", &mut ar);
+            let id = L.topointer(-1) as usize;
+            L.pop(1);
+            let frame = Frame::from_debug(&ar);
+            // prefer the name the function was called by elsewhere
+            let label = match self.index.get(&(id, frame.short_src.clone(), frame.linedefined)) {
+                Some(&i) => self.functions[i].label(),
+                None => {
+                    let name = frame.name.as_ref().map_or("?", |s| &s[..]);
+                    label(name, &frame.short_src, frame.linedefined, &frame.what)
+                }
+            };
+            labels.push(label.replace(';', ","));
+            level += 1;
+        }
+        if labels.is_empty() {
+            return;
+        }
+        labels.reverse();
+        *self.samples.entry(labels.join(";")).or_insert(0) += 1;
+    }
+
+    fn into_profile(self) -> Profile {
+        // sort by inclusive time, and renumber the call edges to match
+        let mut order: Vec<usize> = (0..self.functions.len()).collect();
+        order.sort_by(|&a, &b| self.functions[b].inclusive.cmp(&self.functions[a].inclusive));
+        let mut new_index = vec![0; order.len()];
+        for (new, &old) in order.iter().enumerate() {
+            new_index[old] = new;
+        }
+        let functions = order.iter().map(|&i| self.functions[i].clone()).collect();
+        let mut calls: Vec<(usize, usize, u64, Duration)> = self.calls.into_iter()
+            .map(|((caller, callee), (n, time))| (new_index[caller], new_index[callee], n, time))
+            .collect();
+        calls.sort_by_key(|&(caller, callee, _, _)| (caller, callee));
+        Profile{ functions: functions, calls: calls, samples: self.samples }
+    }
+}
+
+/// Returns the address of the function of the event.
+unsafe fn function_id(L: &mut ExternState, ev: &HookEvent) -> usize {
+    L.checkstack_(1);
+    ev.getinfo(L, "f");
+    let id = L.topointer(-1) as usize;
+    L.pop(1);
+    id
+}
+
+fn label(name: &str, source: &str, linedefined: i32, what: &str) -> String {
+    match what {
+        "main" => format!("main chunk ({})", source),
+        "C" => format!("{} [C]", name),
+        "tail" => "(tail call)".to_string(),
+        _ => format!("{} ({}:{})", name, source, linedefined)
+    }
+}
+
+fn line(f: &FunctionStats) -> i32 {
+    if f.linedefined > 0 { f.linedefined } else { 0 }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_micros() as u64
+}
@@ -0,0 +1,194 @@
+//! A read-eval-print loop, like the one of the standalone lua interpreter
+//!
+//! Lines starting with '=' are shorthand for `return`, so `=x` prints the
+//! value of x. A line that does not make a complete chunk is continued on the
+//! next lines, and the values returned by each chunk are printed.
+//!
+//!   let mut L = lua::State::new();
+//!   L.openlibs();
+//!   lua::repl::stdio().run(&mut L).unwrap();
+
+use std::io::{self, BufRead, BufReader, Stdin, Stdout, Write};
+
+use {State, LoadError, MULTRET};
+
+/// A source of lines for a Repl, such as a terminal with line editing.
+pub trait Input {
+    /// Shows `prompt` and reads a line, without its line terminator. Returns
+    /// None at the end of the input.
+    fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>>;
+
+    /// Called with each chunk entered, possibly spanning several lines, so
+    /// it can be added to the history of a line editor.
+    fn add_history(&mut self, _chunk: &str) {}
+}
+
+/// An Input reading lines from a reader, and writing prompts to a writer
+#[derive(Debug)]
+pub struct LineInput<R, W> {
+    reader: R,
+    prompts: W
+}
+
+impl<R: BufRead, W: Write> LineInput<R, W> {
+    /// Returns an input reading lines from `reader`, and writing prompts to
+    /// `prompts`.
+    pub fn new(reader: R, prompts: W) -> LineInput<R, W> {
+        LineInput{ reader: reader, prompts: prompts }
+    }
+}
+
+impl<R: BufRead, W: Write> Input for LineInput<R, W> {
+    fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        self.prompts.write_all(prompt.as_bytes())?;
+        self.prompts.flush()?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+}
+
+/// A read-eval-print loop reading chunks from an Input, and writing their
+/// results and errors to a writer.
+#[derive(Debug)]
+pub struct Repl<I, W> {
+    input: I,
+    output: W,
+    prompt: String,
+    prompt2: String,
+    chunkname: String,
+    history: Vec<String>
+}
+
+/// Returns a Repl reading from stdin and writing to stdout.
+pub fn stdio() -> Repl<LineInput<BufReader<Stdin>, Stdout>, Stdout> {
+    Repl::new(LineInput::new(BufReader::new(io::stdin()), io::stdout()), io::stdout())
+}
+
+impl<I: Input, W: Write> Repl<I, W> {
+    /// Returns a Repl reading chunks from `input`, and writing results and
+    /// errors to `output`. The prompts are "> " and ">> ", and chunks are
+    /// named "=stdin".
+    pub fn new(input: I, output: W) -> Repl<I, W> {
+        Repl{
+            input: input,
+            output: output,
+            prompt: "> ".to_string(),
+            prompt2: ">> ".to_string(),
+            chunkname: "=stdin".to_string(),
+            history: Vec::new()
+        }
+    }
+
+    /// Sets the prompt of the first line of a chunk, and the prompt of its
+    /// continuation lines.
+    pub fn prompts(mut self, prompt: &str, prompt2: &str) -> Repl<I, W> {
+        self.prompt = prompt.to_string();
+        self.prompt2 = prompt2.to_string();
+        self
+    }
+
+    /// Sets the name given to the chunks entered, as used by loadbuffer().
+    pub fn chunkname(mut self, chunkname: &str) -> Repl<I, W> {
+        self.chunkname = chunkname.to_string();
+        self
+    }
+
+    /// Returns the chunks entered so far, oldest first.
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Runs chunks until the end of the input.
+    pub fn run(&mut self, L: &mut State) -> io::Result<()> {
+        while self.step(L)? {}
+        Ok(())
+    }
+
+    /// Reads, runs and prints one chunk. Returns false at the end of the
+    /// input.
+    ///
+    /// The stack of `L` is left as it was found.
+    pub fn step(&mut self, L: &mut State) -> io::Result<bool> {
+        let mut entered = match self.input.read_line(&self.prompt)? {
+            Some(line) => line,
+            None => return Ok(false)
+        };
+        let base = L.gettop();
+        let loaded = loop {
+            let code = match entered.strip_prefix('=') {
+                Some(expr) => format!("return {}", expr),
+                None => entered.clone()
+            };
+            match L.loadbuffer(&code, &self.chunkname) {
+                Ok(()) => break true,
+                Err(LoadError::ErrSyntax) if incomplete(L) => {
+                    if let Some(line) = self.input.read_line(&self.prompt2)? {
+                        L.pop(1);
+                        entered.push('\n');
+                        entered.push_str(&line);
+                        continue;
+                    }
+                }
+                Err(_) => ()
+            }
+            let msg = L.describe(-1);
+            writeln!(self.output, "{}", msg)?;
+            break false;
+        };
+        if !entered.trim().is_empty() {
+            self.input.add_history(&entered);
+            self.history.push(entered);
+        }
+        if loaded {
+            match L.pcall_(0, MULTRET, true) {
+                Ok(()) => self.print_results(L, base)?,
+                Err(err) => writeln!(self.output, "{}", err)?
+            }
+        }
+        L.settop(base);
+        self.output.flush()?;
+        Ok(true)
+    }
+
+    /// Prints the values above `base` like print(), converting them with
+    /// tostring().
+    fn print_results(&mut self, L: &mut State, base: i32) -> io::Result<()> {
+        let top = L.gettop();
+        if top == base {
+            return Ok(());
+        }
+        let mut values = Vec::new();
+        L.checkstack_(2);
+        for idx in base + 1..top + 1 {
+            L.getglobal("tostring");
+            let s = if L.isfunction(-1) {
+                L.pushvalue(idx);
+                if L.pcall(1, 1, 0).is_ok() && L.isstring(-1) {
+                    L.describe(-1)
+                } else {
+                    L.describe(idx)
+                }
+            } else {
+                L.describe(idx)
+            };
+            L.pop(1);
+            values.push(s);
+        }
+        writeln!(self.output, "{}", values.join("\t"))
+    }
+}
+
+/// Returns whether the syntax error on top of the stack is about an
+/// unexpected end of the chunk, so more lines could complete it.
+fn incomplete(L: &mut State) -> bool {
+    L.describe(-1).ends_with("'<eof>'")
+}
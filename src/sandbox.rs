@@ -0,0 +1,243 @@
+//! Restricted global environments for untrusted code
+
+use libc;
+use libc::c_int;
+use std::collections::BTreeSet;
+
+use {State, RawState, Error, ErrorKind, Type, MULTRET, REGISTRYINDEX};
+use raw;
+
+// Globals exposed by default. Tables are copied, so a sandboxed chunk cannot
+// modify the libraries seen by the host or by other sandboxes.
+const DEFAULT_ALLOW: &'static [&'static str] = &[
+    "_VERSION", "assert", "error", "ipairs", "next", "pairs", "pcall", "rawequal", "rawget",
+    "rawset", "select", "setmetatable", "tonumber", "tostring", "type", "unpack", "xpcall",
+    "coroutine", "math", "string", "table",
+    "os.clock", "os.date", "os.difftime", "os.time"
+];
+
+// Names that are stripped even when an enclosing table is allowed, unless
+// explicitly allowed again
+const DEFAULT_DENY: &'static [&'static str] = &[
+    "collectgarbage", "debug", "dofile", "getfenv", "load", "loadfile", "loadstring",
+    "setfenv", "string.dump"
+];
+
+// The address of this static is the registry key for the weak table mapping
+// each environment to its copy of the string library
+static STRING_LIBS: u8 = 0;
+
+/// Builder for restricted global environments.
+///
+/// A sandbox is a whitelist of global names, e.g. "string", and of fields of
+/// global tables, e.g. "os.time". Every environment it builds is a fresh table
+/// holding copies of the whitelisted values taken from the globals of the
+/// state, so the libraries must have been opened. The environment's `_G`
+/// refers to the environment itself.
+///
+/// The default whitelist holds the safe base functions and the coroutine,
+/// math, string and table libraries, plus os.clock, os.date, os.difftime and
+/// os.time. Functions that load code or reach outside the environment
+/// (loadstring, load, dofile, loadfile, string.dump, debug, getfenv, setfenv)
+/// are never exposed unless allowed explicitly.
+///
+/// Strings share one metatable, whose `__index` gives method calls like
+/// `s:rep(2)` access to the string library. Once a sandbox has built an
+/// environment, methods are looked up in the copy of the string library of
+/// the environment of the calling function instead, so `("").dump` is nil
+/// inside the sandbox while the host keeps the real library.
+#[derive(Clone,Debug)]
+pub struct Sandbox {
+    allow: BTreeSet<String>,
+    deny: BTreeSet<String>
+}
+
+impl Sandbox {
+    /// Returns a sandbox with the default whitelist.
+    pub fn new() -> Sandbox {
+        Sandbox{
+            allow: DEFAULT_ALLOW.iter().map(|s| s.to_string()).collect(),
+            deny: DEFAULT_DENY.iter().map(|s| s.to_string()).collect()
+        }
+    }
+
+    /// Returns a sandbox that exposes nothing.
+    pub fn empty() -> Sandbox {
+        Sandbox{
+            allow: BTreeSet::new(),
+            deny: DEFAULT_DENY.iter().map(|s| s.to_string()).collect()
+        }
+    }
+
+    /// Exposes the global `path` ("name" or "table.field").
+    pub fn allow(mut self, path: &str) -> Sandbox {
+        self.deny.remove(path);
+        self.allow.insert(path.to_string());
+        self
+    }
+
+    /// Hides the global `path` ("name" or "table.field"), even if an
+    /// enclosing table is allowed.
+    pub fn deny(mut self, path: &str) -> Sandbox {
+        self.allow.remove(path);
+        self.deny.insert(path.to_string());
+        self
+    }
+
+    /// Builds a new environment table and pushes it onto the stack of `L`.
+    pub fn push_env(&self, L: &mut State) {
+        L.checkstack_(8);
+        L.createtable(0, self.allow.len() as i32);
+        let env = L.gettop();
+        // whole globals first, so that fields can be added to copied tables
+        for name in self.allow.iter().filter(|p| !p.contains('.')) {
+            L.getglobal(name);
+            if L.istable(-1) {
+                self.copy_table(L, name);
+            }
+            L.setfield(env, name);
+        }
+        for path in self.allow.iter().filter(|p| p.contains('.')) {
+            let (table, field) = path.split_at(path.find('.').unwrap());
+            let field = &field[1..];
+            L.getglobal(table);
+            if !L.istable(-1) {
+                L.pop(1);
+                continue;
+            }
+            L.getfield(-1, field);
+            L.getfield(env, table);
+            if !L.istable(-1) {
+                L.pop(1);
+                L.newtable();
+                L.pushvalue(-1);
+                L.setfield(env, table);
+            }
+            L.insert(-2);
+            L.setfield(-2, field);
+            L.pop(2);
+        }
+        L.pushvalue(env);
+        L.setfield(env, "_G");
+        L.getfield(env, "string");
+        if L.istable(-1) {
+            push_string_libs(L);
+            L.pushvalue(env);
+            L.pushvalue(-3);
+            L.rawset(-3);
+            L.pop(1);
+        }
+        L.pop(1);
+    }
+
+    /// Loads `code` as a text chunk whose environment is a new environment
+    /// table, and pushes the compiled chunk onto the stack without running
+    /// it. This allows running it with pcall_budget().
+    ///
+    /// Binary chunks are refused with an error of kind Syntax.
+    pub fn load(&self, L: &mut State, code: &str, chunkname: &str) -> Result<(),Error> {
+        if code.starts_with('\x1b') {
+            return Err(Error::new(ErrorKind::Syntax, "attempt to load a binary chunk"));
+        }
+        L.loadbuffer_(code, chunkname)?;
+        self.push_env(L);
+        L.setfenv(-2);
+        Ok(())
+    }
+
+    /// Loads and runs `code` in a new environment table, returning all of its
+    /// results on the stack, or an Error. See load().
+    pub fn run(&self, L: &mut State, code: &str, chunkname: &str) -> Result<(),Error> {
+        self.load(L, code, chunkname)?;
+        L.pcall_(0, MULTRET, false)
+    }
+
+    /// Replaces the table on top of the stack with a shallow copy of it,
+    /// leaving out the denied fields.
+    fn copy_table(&self, L: &mut State, name: &str) {
+        let src = L.gettop();
+        L.newtable();
+        let dst = L.gettop();
+        L.pushnil();
+        while L.next(src) {
+            let denied = match L.type_(-2) {
+                Some(Type::String) => {
+                    let key = L.tostring(-2).map(|k| format!("{}.{}", name, k));
+                    key.map_or(false, |k| self.deny.contains(&k))
+                }
+                _ => false
+            };
+            if denied {
+                L.pop(1);
+            } else {
+                L.pushvalue(-2);
+                L.insert(-2);
+                L.rawset(dst);
+            }
+        }
+        L.replace(src);
+    }
+}
+
+impl Default for Sandbox {
+    fn default() -> Sandbox {
+        Sandbox::new()
+    }
+}
+
+/// Pushes the table mapping environments to their string library, creating
+/// it on first use. Creating it also makes the __index of strings look up
+/// methods in that table with string_index().
+fn push_string_libs(L: &mut State) {
+    let key = &STRING_LIBS as *const u8 as *mut libc::c_void;
+    L.pushlightuserdata(key);
+    L.rawget(REGISTRYINDEX);
+    if !L.isnil(-1) {
+        return;
+    }
+    L.pop(1);
+    L.newtable();
+    // weak keys, so that environments can still be collected
+    L.createtable(0, 1);
+    L.pushstring("k");
+    L.setfield(-2, "__mode");
+    L.setmetatable(-2);
+    L.pushlightuserdata(key);
+    L.pushvalue(-2);
+    L.rawset(REGISTRYINDEX);
+    L.pushstring("");
+    if L.getmetatable(-1) {
+        L.getfield(-1, "__index");
+        L.pushvalue(-4);
+        L.pushcclosure(string_index, 2);
+        L.setfield(-2, "__index");
+        L.pop(1);
+    }
+    L.pop(1);
+}
+
+/// The __index of strings: looks up key 2 in the string library of the
+/// environment of the calling function, found in the table in upvalue 2, or
+/// in the original __index in upvalue 1 if that environment is not a
+/// sandbox.
+unsafe extern "C" fn string_index(L: *mut raw::lua_State) -> c_int {
+    let mut L = RawState::from_lua_State(L);
+    L.settop(2);
+    match L.getstack(1) {
+        Some(mut ar) => {
+            L.getinfo("f", &mut ar);
+            L.getfenv(-1);
+            L.rawget(raw::lua_upvalueindex(2));
+        }
+        None => L.pushnil()
+    }
+    if L.istable(-1) {
+        L.pushvalue(2);
+        L.rawget(-2);
+    } else {
+        L.pushvalue(raw::lua_upvalueindex(1));
+        L.pushvalue(2);
+        L.gettable(-2);
+    }
+    1
+}
@@ -0,0 +1,184 @@
+//! require() searchers for modules that do not come from LUA_PATH
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use {State, ExternState, RawState};
+
+/// A place require() can find Lua modules in, such as a map of embedded
+/// scripts or an archive. Add it to a State with add_module_source().
+///
+/// Closures taking the module name and returning an Option<Chunk> are
+/// module sources as well.
+pub trait ModuleSource {
+    /// Returns the code of module `name` (e.g. "a.b"), or None if this
+    /// source does not have it.
+    fn find(&mut self, name: &str) -> Option<Chunk>;
+
+    /// Describes where module `name` was looked for, for the message of a
+    /// failed require(). Each line starts with "\n\t".
+    fn not_found(&self, name: &str) -> String {
+        format!("\n\tno module '{}' in embedded sources", name)
+    }
+}
+
+/// The code of a module, and the name of its chunk
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Chunk {
+    /// The chunk name given to loadbuffer(), e.g. "@a/b.lua" so that errors
+    /// point at "a/b.lua"
+    pub name: String,
+    /// The Lua source code
+    pub code: Cow<'static, str>
+}
+
+impl Chunk {
+    /// Returns the chunk of module `module`, named after its logical path:
+    /// module "a.b" is named "@a/b.lua".
+    pub fn new<C: Into<Cow<'static, str>>>(module: &str, code: C) -> Chunk {
+        Chunk{ name: format!("@{}.lua", module.replace('.', "/")), code: code.into() }
+    }
+}
+
+impl<F: FnMut(&str) -> Option<Chunk>> ModuleSource for F {
+    fn find(&mut self, name: &str) -> Option<Chunk> {
+        self(name)
+    }
+}
+
+/// A module source holding the code of its modules, e.g. scripts embedded
+/// with include_str!().
+#[derive(Clone,Debug,Default)]
+pub struct MemorySource {
+    modules: HashMap<String, Cow<'static, str>>
+}
+
+impl MemorySource {
+    /// Returns a source without modules.
+    pub fn new() -> MemorySource {
+        MemorySource::default()
+    }
+
+    /// Adds module `name` with the given code, replacing any module of the
+    /// same name.
+    pub fn insert<C: Into<Cow<'static, str>>>(&mut self, name: &str, code: C) {
+        self.modules.insert(name.to_string(), code.into());
+    }
+}
+
+impl ModuleSource for MemorySource {
+    fn find(&mut self, name: &str) -> Option<Chunk> {
+        self.modules.get(name).map(|code| Chunk::new(name, code.clone()))
+    }
+}
+
+/// A module source reading modules from a directory, like package.path
+/// but independent of LUA_PATH.
+#[derive(Clone,Debug)]
+pub struct DirSource {
+    dir: PathBuf,
+    template: String
+}
+
+impl DirSource {
+    /// Returns a source reading modules from `dir` with the template
+    /// "?.lua;?/init.lua".
+    pub fn new<P: Into<PathBuf>>(dir: P) -> DirSource {
+        DirSource{ dir: dir.into(), template: "?.lua;?/init.lua".to_string() }
+    }
+
+    /// Sets the template of module paths, relative to the directory. Like
+    /// package.path, it is a list of paths separated by ';', in which '?' is
+    /// replaced by the module name with each '.' replaced by '/'.
+    pub fn template(mut self, template: &str) -> DirSource {
+        self.template = template.to_string();
+        self
+    }
+
+    fn paths(&self, name: &str) -> Vec<PathBuf> {
+        let name = name.replace('.', "/");
+        self.template.split(';').filter(|t| !t.is_empty())
+            .map(|t| self.dir.join(t.replace('?', &name)))
+            .collect()
+    }
+}
+
+impl ModuleSource for DirSource {
+    fn find(&mut self, name: &str) -> Option<Chunk> {
+        self.paths(name).into_iter().filter_map(|path| {
+            fs::read_to_string(&path).ok().map(|code| {
+                Chunk{ name: format!("@{}", path.display()), code: code.into() }
+            })
+        }).next()
+    }
+
+    fn not_found(&self, name: &str) -> String {
+        self.paths(name).iter().map(|path| format!("\n\tno file '{}'", path.display())).collect()
+    }
+}
+
+impl State {
+    /// Adds `source` to the searchers of require(), in `package.loaders`.
+    ///
+    /// The source is searched right after `package.preload`, before
+    /// package.path and package.cpath, so modules it has are found whatever
+    /// LUA_PATH says. Sources added later are searched first.
+    ///
+    /// Fails the task if `package.loaders` is not a table, e.g. because the
+    /// package library is not open.
+    pub fn add_module_source<S: ModuleSource + 'static>(&mut self, source: S) {
+        #![inline(always)]
+        unsafe { self.as_extern().add_module_source(source) }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn add_module_source<S: ModuleSource + 'static>(&mut self, source: S) {
+        self.checkstack_(2);
+        self.getglobal("package");
+        luaassert!(self, self.istable(-1), "add_module_source: package library not open");
+        self.getfield(-1, "loaders");
+        luaassert!(self, self.istable(-1), "add_module_source: package.loaders is not a table");
+        self.pop(2);
+        // the searcher, the tables, and 4 slots for creating the searcher
+        self.checkstack_(8);
+        self.as_raw().add_module_source(source)
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn add_module_source<S: ModuleSource + 'static>(&mut self, mut source: S) {
+        self.getglobal("package");
+        self.getfield(-1, "loaders");
+        // shift the searchers after package.preload up
+        let n = self.objlen(-1) as i32;
+        for i in (2..n + 1).rev() {
+            self.rawgeti(-1, i);
+            self.rawseti(-2, i + 1);
+        }
+        self.push_closure(move |L: &mut ExternState| {
+            let name = L.checkstring(1).unwrap_or("").to_string();
+            let chunk = match source.find(&name) {
+                Some(chunk) => chunk,
+                None => {
+                    L.pushstring(&source.not_found(&name));
+                    return 1;
+                }
+            };
+            if L.loadbuffer(&chunk.code, &chunk.name).is_err() {
+                let msg = format!("error loading module '{}' from '{}':\n\t{}",
+                                  name, chunk.name.trim_start_matches('@'), L.describe(-1));
+                L.pushstring(&msg);
+                drop((name, chunk, msg));
+                L.error();
+            }
+            1
+        });
+        self.rawseti(-2, 2);
+        self.pop(2);
+    }
+}
@@ -0,0 +1,161 @@
+//! Loading chunks from io::Read and dumping functions to io::Write
+
+use libc;
+use std::any::Any;
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+use {State, ExternState, RawState, Error};
+use raw;
+
+// Size of the buffer used by load_from()
+const READ_BUFFER_SIZE: usize = 8192;
+
+struct ReadState<R> {
+    reader: R,
+    buf: Vec<u8>,
+    err: Option<io::Error>,
+    panic: Option<Box<dyn Any + Send>>
+}
+
+struct WriteState<W> {
+    writer: W,
+    err: Option<io::Error>,
+    panic: Option<Box<dyn Any + Send>>
+}
+
+impl State {
+    /// Loads a Lua chunk read from `reader`, like load(). If there are no
+    /// errors, the compiled chunk is pushed as a Lua function on top of the
+    /// stack.
+    ///
+    /// An error returned by the reader stops loading and is returned as an
+    /// Error of kind Io. A panic in the reader is resumed once Lua is no longer
+    /// on the stack.
+    ///
+    /// Fails the task if `chunkname` contains interior NULs.
+    pub fn load_from<R: Read>(&mut self, reader: R, chunkname: &str) -> Result<(),Error> {
+        #![inline(always)]
+        unsafe { self.as_extern().load_from(reader, chunkname) }
+    }
+
+    /// Dumps the function on top of the stack as a binary chunk to `writer`,
+    /// like dump(). The function is not popped.
+    ///
+    /// Returns an Error of kind Io if the writer fails, or if the value is not
+    /// a Lua function.
+    pub fn dump_to<W: Write>(&mut self, writer: W) -> Result<(),Error> {
+        #![inline(always)]
+        unsafe { self.as_extern().dump_to(writer) }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn load_from<R: Read>(&mut self, reader: R, chunkname: &str) -> Result<(),Error> {
+        self.checkstack_(1);
+        self.as_raw().load_from(reader, chunkname)
+    }
+
+    pub unsafe fn dump_to<W: Write>(&mut self, writer: W) -> Result<(),Error> {
+        luaassert!(self, self.gettop() >= 1, "dump_to: stack underflow");
+        self.as_raw().dump_to(writer)
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn load_from<R: Read>(&mut self, reader: R, chunkname: &str) -> Result<(),Error> {
+        let mut st = ReadState{
+            reader: reader,
+            buf: vec![0; READ_BUFFER_SIZE],
+            err: None,
+            panic: None
+        };
+        let data = &mut st as *mut ReadState<R> as *mut libc::c_void;
+        let res = self.load_(read_trampoline::<R>, data, chunkname);
+        if st.panic.is_some() || st.err.is_some() {
+            // the chunk was cut short, so discard whatever Lua made of it
+            if res.is_ok() {
+                self.pop(1);
+            }
+            if let Some(payload) = st.panic {
+                panic::resume_unwind(payload);
+            }
+        }
+        match st.err {
+            Some(err) => Err(err.into()),
+            None => res
+        }
+    }
+
+    pub unsafe fn dump_to<W: Write>(&mut self, writer: W) -> Result<(),Error> {
+        let mut st = WriteState{ writer: writer, err: None, panic: None };
+        let data = &mut st as *mut WriteState<W> as *mut libc::c_void;
+        let res = self.dump(write_trampoline::<W>, data);
+        if let Some(payload) = st.panic {
+            panic::resume_unwind(payload);
+        }
+        if let Some(err) = st.err {
+            return Err(err.into());
+        }
+        match res {
+            Ok(()) => st.writer.flush().map_err(Error::from),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                         "unable to dump given function").into())
+        }
+    }
+}
+
+unsafe extern "C" fn read_trampoline<R: Read>(_L: *mut raw::lua_State, ud: *mut libc::c_void,
+                                              sz: *mut libc::size_t) -> *const libc::c_char {
+    let st = &mut *(ud as *mut ReadState<R>);
+    *sz = 0;
+    if st.err.is_some() || st.panic.is_some() {
+        return ptr::null();
+    }
+    let (reader, buf) = (&mut st.reader, &mut st.buf);
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        loop {
+            match reader.read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => return res
+            }
+        }
+    }));
+    match res {
+        Ok(Ok(0)) => ptr::null(),
+        Ok(Ok(n)) => {
+            *sz = n as libc::size_t;
+            st.buf.as_ptr() as *const libc::c_char
+        }
+        Ok(Err(err)) => {
+            st.err = Some(err);
+            ptr::null()
+        }
+        Err(payload) => {
+            st.panic = Some(payload);
+            ptr::null()
+        }
+    }
+}
+
+unsafe extern "C" fn write_trampoline<W: Write>(_L: *mut raw::lua_State, p: *const libc::c_void,
+                                                sz: libc::size_t, ud: *mut libc::c_void)
+                                               -> libc::c_int {
+    let st = &mut *(ud as *mut WriteState<W>);
+    let bytes = slice::from_raw_parts(p as *const u8, sz as usize);
+    let writer = &mut st.writer;
+    match panic::catch_unwind(AssertUnwindSafe(|| writer.write_all(bytes))) {
+        Ok(Ok(())) => 0,
+        Ok(Err(err)) => {
+            st.err = Some(err);
+            1
+        }
+        Err(payload) => {
+            st.panic = Some(payload);
+            1
+        }
+    }
+}
@@ -1,10 +1,40 @@
 use State;
+use ExternState;
 use GLOBALSINDEX;
 use Type;
+use FromLuaError;
+use ErrorValue;
+use {Error, ErrorKind, LuaRef};
+use {UserData, UserDataMethods};
+use {StateBuilder, Budget, InterruptHandle, Sandbox};
+use {Resumed, ThreadStatus, Executor};
+use {Frame, Traceback};
+use {DebugEvent, MASKCALL, MASKLINE};
+use {Profiler, Coverage};
+use {Debugger, DebugHandler, Session, PauseReason, Resume, DapServer};
+use {Chunk, MemorySource, DirSource};
+use repl::{Repl, LineInput};
+use dap::{read_body, write_message};
+use json::Json;
 use raw;
 
 use libc;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
+use std::future::Future;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+use std::net::{TcpListener, TcpStream};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
 use std::thread;
+use std::time::Duration;
 
 #[test]
 fn test_state_init() {
@@ -130,3 +160,1276 @@ fn test_gsub() {
     assert_eq!(L.gsub("test", "a", "b"), "test");
     assert_eq!(L.gsub("a b c d e", " ", "."), "a.b.c.d.e");
 }
+
+#[test]
+fn test_push_read() {
+    let mut s = State::new();
+
+    assert_eq!(s.push(42), 1);
+    assert_eq!(s.read::<i32>(-1), Ok(42));
+    assert_eq!(s.read::<f64>(-1), Ok(42.0));
+    // reading a number as a string must not convert the stack value
+    assert_eq!(s.read::<String>(-1), Ok("42".to_string()));
+    assert_eq!(s.type_(-1), Some(Type::Number));
+
+    s.push("foo");
+    assert_eq!(s.read::<String>(-1), Ok("foo".to_string()));
+    assert_eq!(s.read::<Vec<u8>>(-1), Ok(b"foo".to_vec()));
+    assert_eq!(s.read::<i32>(-1), Err(FromLuaError{ expected: "i32", got: "string" }));
+
+    s.push(1.5);
+    assert!(s.read::<i64>(-1).is_err());
+    s.push(300);
+    assert!(s.read::<i8>(-1).is_err());
+    s.push(9223372036854775808.0);
+    assert_eq!(s.read::<i64>(-1), Err(FromLuaError{ expected: "i64", got: "out-of-range number" }));
+    s.push(18446744073709551616.0);
+    assert_eq!(s.read::<u64>(-1), Err(FromLuaError{ expected: "u64", got: "out-of-range number" }));
+    s.push(-9223372036854775808.0);
+    assert_eq!(s.read::<i64>(-1), Ok(i64::MIN));
+    s.push(2147483647);
+    assert_eq!(s.read::<i32>(-1), Ok(i32::MAX));
+
+    s.push(None::<bool>);
+    assert_eq!(s.read::<Option<bool>>(-1), Ok(None));
+    assert_eq!(s.read::<Option<bool>>(10), Ok(None));
+    s.push(Some(true));
+    assert_eq!(s.read::<Option<bool>>(-1), Ok(Some(true)));
+
+    assert_eq!(s.push((1, "two", false)), 3);
+    assert_eq!(s.read::<(i32, String, bool)>(-3), Ok((1, "two".to_string(), false)));
+}
+
+#[test]
+fn test_push_read_tables() {
+    let mut s = State::new();
+
+    s.push(vec![1, 2, 3]);
+    assert_eq!(s.type_(-1), Some(Type::Table));
+    assert_eq!(s.objlen(-1), 3);
+    assert_eq!(s.read::<Vec<i32>>(-1), Ok(vec![1, 2, 3]));
+    assert_eq!(s.gettop(), 1);
+
+    let mut map = HashMap::new();
+    map.insert("a".to_string(), vec![1.5]);
+    map.insert("b".to_string(), vec![]);
+    s.push(&map);
+    assert_eq!(s.read::<HashMap<String, Vec<f64>>>(-1), Ok(map));
+    assert!(s.read::<HashMap<String, String>>(-1).is_err());
+    assert_eq!(s.gettop(), 2);
+}
+
+#[test]
+fn test_closure() {
+    let calls = Rc::new(Cell::new(0));
+    {
+        let mut s = State::new();
+        s.open_base();
+
+        let mut total = 0;
+        let c = calls.clone();
+        s.register_closure("add", move |L| unsafe {
+            c.set(c.get() + 1);
+            total += L.checkinteger(1);
+            L.push(total as i32)
+        });
+        assert!(s.dostring("add(2); result = add(3)"));
+        s.getglobal("result");
+        assert_eq!(s.read::<i32>(-1), Ok(5));
+        assert_eq!(calls.get(), 2);
+        assert_eq!(Rc::strong_count(&calls), 2);
+
+        // calling back into the same closure is an error, which leaves the
+        // closure callable afterwards
+        s.register_closure("callback", |L| unsafe {
+            L.pushvalue(1);
+            L.call(0, 0);
+            L.push((1, 2))
+        });
+        assert!(s.dostring("function f() callback(f) end \
+                            ok, err = pcall(f) \
+                            x, y = callback(function () end)"));
+        s.getglobal("err");
+        assert!(s.describe(-1).ends_with(":1: cannot call a Rust closure that is already running"));
+        s.getglobal("y");
+        assert_eq!(s.read::<i32>(-1), Ok(2));
+
+        // errors raised by the closure point at its caller
+        s.register_closure("clos", |L| unsafe {
+            match L.checkinteger(1) {
+                1 => L.errorstr("boom"),
+                2 => panic!("oops"),
+                _ => 0
+            }
+        });
+        for &(arg, expected) in &[("'x'", "chunk:1: bad argument #1 to 'clos' (number expected, got string)"),
+                                  ("1", "chunk:1: boom"),
+                                  ("2", "chunk:1: rust panic: oops")] {
+            assert!(s.loadbuffer(&format!("clos({})", arg), "=chunk").is_ok());
+            assert!(s.pcall(0, 0, 0).is_err());
+            assert_eq!(s.describe(-1), expected);
+            s.pop(1);
+        }
+    }
+    // closing the state drops the closure
+    assert_eq!(Rc::strong_count(&calls), 1);
+}
+
+lua_extern! {
+    unsafe fn panicky(L: &mut ExternState) -> i32 {
+        let v = vec![L.checkinteger(1)];
+        panic!("boom {}", v[0]);
+    }
+}
+
+#[test]
+fn test_extern_panic() {
+    let mut s = State::new();
+    s.open_base();
+    s.register("panicky", panicky);
+    assert!(s.dostring("ok, err = pcall(panicky, 7)"));
+    s.getglobal("ok");
+    assert_eq!(s.read::<bool>(-1), Ok(false));
+    s.getglobal("err");
+    assert_eq!(s.read::<String>(-1), Ok("rust panic: boom 7".to_string()));
+
+    s.push_closure(|_| panic!("closure"));
+    assert!(s.pcall(0, 0, 0).is_err());
+    assert_eq!(s.read::<String>(-1), Ok("rust panic: closure".to_string()));
+}
+
+lua_extern! {
+    unsafe fn open_answer(L: &mut ExternState) -> i32 {
+        L.createtable(0, 1);
+        L.pushinteger(42);
+        L.setfield(-2, "value");
+        1
+    }
+
+    unsafe fn twice(L: &mut ExternState) -> i32 {
+        let n = L.checkinteger(1);
+        L.pushinteger(n * 2);
+        1
+    }
+}
+
+#[test]
+fn test_preload() {
+    let mut s = State::new();
+    s.openlibs();
+    s.preload("answer", open_answer);
+    let opened = Rc::new(Cell::new(0));
+    let count = opened.clone();
+    s.preload_closure("counter", move |L| unsafe {
+        count.set(count.get() + 1);
+        let name = L.checkstring(1).unwrap_or("").to_string();
+        L.push(name);
+        1
+    });
+    s.preloadlib("mathx", &[("twice", twice)]);
+    assert_eq!(s.gettop(), 0);
+
+    // nothing is opened or made global until required
+    assert!(s.dostring("assert(answer == nil and mathx == nil)"));
+    assert_eq!(opened.get(), 0);
+    assert!(s.dostring("a = require('answer').value
+                        c = require('counter') .. require('counter')
+                        t = require('mathx').twice(21)"));
+    assert_eq!(opened.get(), 1);
+    for &(name, value) in &[("a", "42"), ("c", "countercounter"), ("t", "42")] {
+        s.getglobal(name);
+        assert_eq!(s.read::<String>(-1), Ok(value.to_string()));
+        s.pop(1);
+    }
+}
+
+lua_extern! {
+    unsafe fn area(L: &mut ExternState) -> i32 {
+        let (w, h) = (L.checknumber(1), L.checknumber(2));
+        L.pushnumber(w * h);
+        1
+    }
+
+    unsafe fn perimeter(L: &mut ExternState) -> i32 {
+        let (w, h) = (L.checknumber(1), L.checknumber(2));
+        L.pushnumber(2.0 * (w + h));
+        1
+    }
+}
+
+lua_module! {
+    luaopen_geometry = "geometry" { area, perimeter }
+}
+
+#[test]
+fn test_lua_module() {
+    // resolve the exported symbol by name, as the loader of a C module does
+    extern "C" {
+        fn luaopen_geometry(L: *mut raw::lua_State) -> libc::c_int;
+    }
+
+    let mut s = State::new();
+    s.openlibs();
+    s.preload("geometry", luaopen_geometry);
+    assert!(s.dostring("local g = require('geometry')\n\
+                        assert(g == geometry and package.loaded.geometry == g)\n\
+                        a, p = g.area(3, 4), g.perimeter(3, 4)"));
+    s.getglobal("a");
+    assert_eq!(s.read::<i32>(-1), Ok(12));
+    s.getglobal("p");
+    assert_eq!(s.read::<i32>(-1), Ok(14));
+}
+
+#[test]
+fn test_module_source() {
+    let mut s = State::new();
+    s.openlibs();
+    assert!(s.dostring("package.path = ''"));
+    let mut embedded = MemorySource::new();
+    embedded.insert("a.b", "return { answer = 42 }");
+    embedded.insert("a.broken", "local x = 1\nerror('oops')");
+    s.add_module_source(embedded);
+    s.add_module_source(|name: &str| {
+        if name == "gen" { Some(Chunk::new(name, format!("return '{}'", name))) } else { None }
+    });
+    let dir = env::temp_dir().join(format!("lua-searcher-{}", process::id()));
+    fs::create_dir_all(dir.join("pkg")).unwrap();
+    fs::write(dir.join("pkg").join("init.lua"), "return 'from dir'").unwrap();
+    s.add_module_source(DirSource::new(&dir).template("?.luax;?/init.lua"));
+
+    assert!(s.dostring("answer = require('a.b').answer\n\
+                        gen = require('gen')\n\
+                        dir = require('pkg')\n\
+                        ok, broken = pcall(require, 'a.broken')\n\
+                        ok, missing = pcall(require, 'nope')"));
+    fs::remove_dir_all(&dir).unwrap();
+    for &(name, value) in &[("answer", "42"), ("gen", "gen"), ("dir", "from dir"), ("broken", "a/broken.lua:2: oops")] {
+        s.getglobal(name);
+        assert_eq!(s.read::<String>(-1), Ok(value.to_string()));
+        s.pop(1);
+    }
+    s.getglobal("missing");
+    let missing = s.read::<String>(-1).unwrap();
+    assert!(missing.contains("\n\tno module 'nope' in embedded sources"), "{}", missing);
+    assert!(missing.contains(&format!("\n\tno file '{}'", dir.join("nope.luax").display())), "{}", missing);
+}
+
+struct DropFlag(Rc<Cell<bool>>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.set(true);
+    }
+}
+
+thread_local!(static DROPPED: Rc<Cell<bool>> = Rc::new(Cell::new(false)));
+
+lua_extern! {
+    unsafe fn parse_int(L: &mut ExternState) -> Result<i32, ::std::num::ParseIntError> {
+        let _flag = DROPPED.with(|d| DropFlag(d.clone()));
+        let n: i32 = L.checkstring(1).unwrap_or("").parse()?;
+        L.push(n);
+        Ok(1)
+    }
+
+    unsafe fn raise_table(L: &mut ExternState) -> Result<i32, ErrorValue<Vec<i32>>> {
+        let v = vec![L.checkinteger(1) as i32];
+        Err(ErrorValue(v))
+    }
+}
+
+#[test]
+fn test_extern_result() {
+    let mut s = State::new();
+    s.open_base();
+    s.register("parse_int", parse_int);
+    s.register("raise_table", raise_table);
+    assert!(s.dostring("n = parse_int('42')"));
+    s.getglobal("n");
+    assert_eq!(s.read::<i32>(-1), Ok(42));
+    s.pop(1);
+
+    DROPPED.with(|d| d.set(false));
+    assert!(s.dostring("ok, err = pcall(parse_int, 'x')"));
+    assert!(DROPPED.with(|d| d.get()));
+    s.getglobal("err");
+    assert_eq!(s.read::<String>(-1), Ok("invalid digit found in string".to_string()));
+    s.pop(1);
+
+    assert!(s.dostring("ok, err = pcall(raise_table, 3)"));
+    s.getglobal("err");
+    assert_eq!(s.read::<Vec<i32>>(-1), Ok(vec![3]));
+}
+
+fn run(s: &mut State, code: &str) -> Result<(), Error> {
+    s.loadstring_(code)?;
+    s.pcall_(0, 0, true)
+}
+
+#[test]
+fn test_pcall_error() {
+    let mut s = State::new();
+    s.open_base();
+
+    let err = s.loadstring_("x = = 1").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Syntax);
+    assert!(err.message().contains("unexpected symbol"));
+    assert_eq!(s.gettop(), 0);
+
+    let err = run(&mut s, "local function f() error('oops') end\nf()").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Runtime);
+    assert_eq!(err.message(), "[string \"local function f() error('oops') end...\"]:1: oops");
+    let tb = err.traceback().unwrap();
+    assert!(tb.starts_with("stack traceback:\n\t[C]: in function 'error'"));
+    assert!(tb.contains(":1: in function 'f'"));
+    assert!(tb.contains(":2: in main chunk"));
+    assert_eq!(err.to_string(), format!("{}\n{}", err.message(), tb));
+    let frames = err.backtrace().unwrap();
+    assert_eq!(frames[0].name, Some("error".to_string()));
+    assert_eq!((frames[1].currentline, frames[1].linedefined), (Some(1), 1));
+    assert_eq!(s.gettop(), 0);
+
+    let err = s.dostring_("error({code = 5})").unwrap_err();
+    assert_eq!(err.message(), "(error object is a table value)");
+    assert!(err.traceback().is_none());
+    s.pushref(err.value().unwrap());
+    s.getfield(-1, "code");
+    assert_eq!(s.read::<i32>(-1), Ok(5));
+    s.pop(2);
+
+    assert!(s.dostring_("return 1, 2").is_ok());
+    assert_eq!(s.gettop(), 2);
+}
+
+#[test]
+fn test_luaref() {
+    let mut s = State::new();
+    s.open_base();
+    assert!(s.dostring("weak = setmetatable({}, {__mode = 'k'}); t = {n = 1}; weak[t] = true"));
+    s.getglobal("t");
+    let r = s.newref();
+    assert_eq!(s.gettop(), 0);
+    assert!(s.dostring("t = nil; collectgarbage()"));
+    s.pushref(&r);
+    s.getfield(-1, "n");
+    assert_eq!(s.read::<i32>(-1), Ok(1));
+    s.pop(2);
+
+    // dropping the ref releases the value the next time the state touches refs
+    let refs: Vec<LuaRef> = vec![r];
+    drop(refs);
+    s.pushnil();
+    let nil = s.newref();
+    assert!(nil.is_nil());
+    assert!(s.dostring("collectgarbage(); empty = next(weak) == nil"));
+    s.getglobal("empty");
+    assert_eq!(s.read::<bool>(-1), Ok(true));
+}
+
+struct Counter {
+    n: i32,
+    dropped: Rc<Cell<bool>>
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        self.dropped.set(true);
+    }
+}
+
+impl UserData for Counter {
+    const NAME: &'static str = "Counter";
+
+    fn add_methods(methods: &mut UserDataMethods<Counter>) {
+        unsafe fn incr(L: &mut ExternState, c: &mut Counter) -> i32 {
+            c.n += L.optinteger(2, 1) as i32;
+            0
+        }
+        unsafe fn get(L: &mut ExternState, c: &mut Counter) -> i32 {
+            L.push(c.n)
+        }
+        unsafe fn add(L: &mut ExternState, c: &mut Counter) -> i32 {
+            c.n += L.check_userdata::<Counter>(2).n;
+            0
+        }
+        unsafe fn call(L: &mut ExternState, _: &mut Counter) -> i32 {
+            L.pushvalue(2);
+            L.call(0, 0);
+            0
+        }
+        unsafe fn tostring(L: &mut ExternState, c: &mut Counter) -> i32 {
+            L.push(format!("Counter({})", c.n))
+        }
+        unsafe fn index(L: &mut ExternState, _: &mut Counter) -> i32 {
+            let key = L.checkstring(2).unwrap_or("").to_string();
+            L.push(format!("no {}", key))
+        }
+        methods.add_method("incr", incr);
+        methods.add_method("get", get);
+        methods.add_method("add", add);
+        methods.add_method("call", call);
+        methods.add_meta_method("__tostring", tostring);
+        methods.add_meta_method("__index", index);
+    }
+}
+
+struct Other;
+
+impl UserData for Other {
+    const NAME: &'static str = "Counter";
+}
+
+struct Finalizer;
+
+impl UserData for Finalizer {
+    const NAME: &'static str = "Finalizer";
+
+    fn add_methods(methods: &mut UserDataMethods<Finalizer>) {
+        methods.add_meta_function("__gc", panicky);
+    }
+}
+
+struct PanicOnDrop;
+
+impl Drop for PanicOnDrop {
+    fn drop(&mut self) {
+        panic!("drop");
+    }
+}
+
+impl UserData for PanicOnDrop {
+    const NAME: &'static str = "PanicOnDrop";
+}
+
+#[test]
+fn test_userdata() {
+    let dropped = Rc::new(Cell::new(false));
+    {
+        let mut s = State::new();
+        s.open_base();
+        s.push_userdata(Counter{ n: 1, dropped: dropped.clone() });
+        s.setglobal("c");
+        assert!(s.dostring("c:incr(); c:incr(5); n = c:get(); str = tostring(c)"));
+        s.getglobal("n");
+        assert_eq!(s.read::<i32>(-1), Ok(7));
+        s.getglobal("str");
+        assert_eq!(s.read::<String>(-1), Ok("Counter(7)".to_string()));
+        s.pop(2);
+
+        s.getglobal("c");
+        assert_eq!(s.to_userdata::<Counter>(-1).map(|c| c.n), Some(7));
+        // a different type with the same name does not match
+        assert!(s.to_userdata::<Other>(-1).is_none());
+        s.push_userdata(Other);
+        assert!(s.to_userdata::<Counter>(-1).is_none());
+        s.pop(2);
+
+        assert!(s.dostring("ok, err = pcall(c.get, {})"));
+        s.getglobal("err");
+        assert_eq!(s.read::<String>(-1),
+                   Ok("bad argument #1 to '?' (Counter expected, got table)".to_string()));
+        s.pop(1);
+
+        // argument errors name the method, and do not count the userdata
+        assert!(s.loadbuffer("c:incr('x')", "=chunk").is_ok());
+        assert!(s.pcall(0, 0, 0).is_err());
+        assert_eq!(s.describe(-1), "chunk:1: bad argument #1 to 'incr' (number expected, got string)");
+        s.pop(1);
+
+        // __index is called for the keys that are not methods
+        assert!(s.dostring("missing = c.missing"));
+        s.getglobal("missing");
+        assert_eq!(s.read::<String>(-1), Ok("no missing".to_string()));
+        s.pop(1);
+
+        // the value is borrowed while a method runs, even after an error
+        assert!(s.dostring("ok1, err1 = pcall(c.add, c, c) \
+                            ok2, err2 = pcall(c.call, c, function () c:get() end) \
+                            c:incr(); n = c:get()"));
+        s.getglobal("err1");
+        assert_eq!(s.read::<String>(-1),
+                   Ok("bad argument #2 to '?' (attempt to use a Counter that is already in use)"
+                      .to_string()));
+        s.getglobal("err2");
+        assert!(s.describe(-1).ends_with("(attempt to use a Counter that is already in use)"));
+        s.getglobal("n");
+        assert_eq!(s.read::<i32>(-1), Ok(8));
+        s.pop(3);
+        assert!(!dropped.get());
+    }
+    assert!(dropped.get());
+
+    let mut s = State::new();
+    s.open_base();
+    s.push_closure(|L| unsafe { L.push_userdata(Finalizer); 1 });
+    assert!(s.pcall(0, 0, 0).is_err());
+    assert!(s.describe(-1).ends_with("__gc is reserved for dropping Finalizer"));
+    s.pop(1);
+    s.push_userdata(PanicOnDrop);
+    s.pop(1);
+    assert!(s.dostring("ok, err = pcall(collectgarbage)"));
+    s.getglobal("err");
+    assert!(s.describe(-1).ends_with("rust panic: drop"));
+}
+
+#[test]
+fn test_pairs() {
+    let mut s = State::new();
+    assert!(s.dostring("return {10, 20, 30, x = 'a', [4.5] = 'b'}"));
+    assert_eq!(s.gettop(), 1);
+
+    let mut map = HashMap::new();
+    for pair in s.pairs::<String, String>(1) {
+        if let Ok((k, v)) = pair {
+            map.insert(k, v);
+        }
+    }
+    assert_eq!(map.len(), 5);
+    assert_eq!(map.get("x"), Some(&"a".to_string()));
+    // numeric keys are converted without breaking the traversal
+    assert_eq!(map.get("4.5"), Some(&"b".to_string()));
+    assert_eq!(map.get("2"), Some(&"20".to_string()));
+    assert_eq!(s.gettop(), 1);
+
+    // breaking early keeps the stack balanced
+    let first = s.pairs::<(), ()>(-1).next();
+    assert_eq!(first, Some(Ok(((), ()))));
+    assert_eq!(s.gettop(), 1);
+
+    let nums: Vec<i32> = s.ipairs::<i32>(1).map(|v| v.unwrap()).collect();
+    assert_eq!(nums, vec![10, 20, 30]);
+    let bad: Vec<_> = s.pairs::<i32, i32>(1).filter(|p| p.is_err()).collect();
+    assert_eq!(bad.len(), 2);
+    assert_eq!(s.gettop(), 1);
+}
+
+struct FailingReader<'a>(&'a [u8]);
+
+impl<'a> io::Read for FailingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.0.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Other, "connection reset"));
+        }
+        let n = cmp::min(buf.len(), self.0.len());
+        buf[..n].copy_from_slice(&self.0[..n]);
+        self.0 = &self.0[n..];
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_load_dump_stream() {
+    let mut s = State::new();
+    s.load_from(&b"return 6 * 7"[..], "=stream").unwrap();
+    let mut chunk = Vec::new();
+    s.dump_to(&mut chunk).unwrap();
+    s.pop(1);
+    assert!(chunk.starts_with(b"\x1bLua"));
+
+    s.load_from(&chunk[..], "=binary").unwrap();
+    s.call(0, 1);
+    assert_eq!(s.read::<i32>(-1), Ok(42));
+    s.pop(1);
+
+    let err = s.load_from(FailingReader(b"return 1"), "=broken").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Io);
+    assert_eq!(err.message(), "connection reset");
+    assert_eq!(s.gettop(), 0);
+
+    let err = s.load_from(&b"return +"[..], "=bad").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Syntax);
+    assert_eq!(s.gettop(), 0);
+
+    s.open_base();
+    s.getglobal("print");
+    let err = s.dump_to(&mut Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Io);
+}
+
+#[test]
+fn test_memory_limit() {
+    let mut s = StateBuilder::new().memory_limit(256 * 1024).build().unwrap();
+    s.open_base();
+    let stats = s.memory_stats().unwrap();
+    assert!(stats.current > 0 && stats.current <= stats.peak);
+    assert!(stats.allocations > 0);
+    assert_eq!(stats.limit, Some(256 * 1024));
+
+    let err = s.dostring_("local t = {} for i = 1, 1e7 do t[i] = i end").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Memory);
+    let stats = s.memory_stats().unwrap();
+    assert!(stats.peak <= 256 * 1024);
+
+    // the state is still usable once the garbage is collected
+    assert!(s.dostring_("collectgarbage(); x = 1 + 1").is_ok());
+
+    assert!(StateBuilder::new().memory_limit(64).build().is_none());
+    assert!(State::new().memory_stats().is_some());
+}
+
+#[test]
+fn test_budget() {
+    let mut s = State::new();
+    s.open_base();
+
+    assert!(s.loadstring_("local n = 0 for i = 1, 100 do n = n + i end return n").is_ok());
+    assert!(s.pcall_budget(0, 1, Budget::instructions(10000)).is_ok());
+    assert_eq!(s.read::<i32>(-1), Ok(5050));
+    s.pop(1);
+
+    // scripts cannot swallow the error
+    s.loadstring_("while true do pcall(function() while true do end end) end").unwrap();
+    let err = s.pcall_budget(0, 0, Budget::instructions(100000)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::BudgetExceeded);
+
+    s.loadstring_("while true do end").unwrap();
+    let err = s.pcall_budget(0, 0, Budget::time(Duration::from_millis(20))).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::BudgetExceeded);
+    assert_eq!(s.gettop(), 0);
+
+    // the hook is removed afterwards
+    assert_eq!(s.gethookmask(), 0);
+    assert!(s.dostring_("for i = 1, 1e6 do end").is_ok());
+
+    // an existing hook keeps running and is restored
+    s.sethook(line_hook, ::MASKLINE, 0);
+    LINES.with(|l| l.set(0));
+    s.loadstring_("local a = 1\nlocal b = 2\nlocal c = 3").unwrap();
+    assert!(s.pcall_budget(0, 0, Budget::instructions(1000)).is_ok());
+    assert_eq!(LINES.with(|l| l.get()), 3);
+    assert_eq!(s.gethookmask(), ::MASKLINE);
+}
+
+thread_local!(static LINES: Cell<i32> = Cell::new(0));
+
+unsafe extern "C" fn line_hook(_L: *mut raw::lua_State, _ar: *mut raw::lua_Debug) {
+    LINES.with(|l| l.set(l.get() + 1));
+}
+
+#[test]
+fn test_interrupt() {
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    let mut s = State::new();
+    s.openlibs();
+    assert!(s.dostring("early = coroutine.create(function() for i = 1, 1e5 do end end)"));
+    let handle: InterruptHandle = s.interrupt_handle();
+    assert_send_sync(&handle);
+    assert!(s.dostring_("for i = 1, 1e5 do end").is_ok());
+
+    let remote = handle.clone();
+    let t = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        remote.interrupt();
+    });
+    s.loadstring_("while true do pcall(function() while true do end end) end").unwrap();
+    let err = s.pcall_(0, 0, false).unwrap_err();
+    t.join().unwrap();
+    assert_eq!(err.kind(), ErrorKind::Interrupted);
+    // reporting the error clears the interrupt
+    assert!(!handle.is_interrupted());
+    assert!(s.dostring_("x = 1").is_ok());
+
+    // a coroutine created before the handle does not have the hook
+    handle.interrupt();
+    s.loadstring_("return coroutine.resume(early)").unwrap();
+    assert!(s.pcall_(0, 1, false).is_ok());
+    assert_eq!(s.read::<bool>(-1), Ok(true));
+    s.pop(1);
+    handle.clear();
+
+    // budgets still work alongside the interrupt hook
+    s.loadstring_("while true do end").unwrap();
+    let err = s.pcall_budget(0, 0, Budget::instructions(5000)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::BudgetExceeded);
+    assert_eq!(s.gethookmask(), ::MASKCOUNT);
+}
+
+#[test]
+fn test_sandbox() {
+    let mut s = State::new();
+    s.openlibs();
+    let sandbox = Sandbox::new().allow("print").deny("os.date");
+
+    sandbox.run(&mut s, "return type(loadstring), type(string.dump), type(string.rep), \
+                         type(os.time), type(os.date), type(os.execute), type(print), \
+                         type(getfenv), type(io), rawequal(_G, _G._G)", "=plugin").unwrap();
+    let types: Vec<String> = (1..10).map(|i| s.read::<String>(i).unwrap()).collect();
+    assert_eq!(types, vec!["nil", "nil", "function", "function", "nil", "nil", "function",
+                           "nil", "nil"]);
+    assert_eq!(s.read::<bool>(10), Ok(true));
+    s.settop(0);
+
+    // string methods come from the sandbox's string library
+    sandbox.run(&mut s, "local f = function () end \
+                         return type(('').dump), pcall(function () return ('').dump(f) end), \
+                         ('x'):rep(3)", "=plugin").unwrap();
+    assert_eq!(s.read::<String>(1), Ok("nil".to_string()));
+    assert_eq!(s.read::<bool>(2), Ok(false));
+    assert_eq!(s.read::<String>(-1), Ok("xxx".to_string()));
+    s.settop(0);
+    assert!(s.dostring("assert(type(('').dump) == 'function')"));
+
+    // changes stay inside the environment
+    sandbox.run(&mut s, "string.rep = nil; x = 1", "=plugin").unwrap();
+    assert!(s.dostring("assert(string.rep and x == nil)"));
+
+    let err = sandbox.run(&mut s, "\x1bLua", "=binary").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Syntax);
+
+    // the compiled chunk can be run with a budget
+    sandbox.load(&mut s, "while true do end", "=loop").unwrap();
+    let err = s.pcall_budget(0, 0, Budget::instructions(1000)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::BudgetExceeded);
+    assert_eq!(s.gettop(), 0);
+}
+
+lua_extern! {
+    unsafe fn yield_twice(L: &mut ExternState) -> i32 {
+        let n = L.checkinteger(1);
+        L.yield_values((n * 2, "doubled"))
+    }
+}
+
+#[test]
+fn test_thread() {
+    let mut s = State::new();
+    s.openlibs();
+    s.register("yield_twice", yield_twice);
+    assert!(s.loadstring("local a, b = ... \
+                          local c = coroutine.yield(a + b) \
+                          local d = yield_twice(c) \
+                          return d .. '!'").is_ok());
+    let co = s.new_thread();
+    assert_eq!(s.gettop(), 0);
+    assert_eq!(co.status(&mut s), ThreadStatus::Suspended);
+
+    assert_eq!(co.resume(&mut s, (1, 2)).unwrap(), Resumed::Yielded(3));
+    assert_eq!(co.resume(&mut s, 5).unwrap(), Resumed::Yielded((10, "doubled".to_string())));
+    assert_eq!(co.resume(&mut s, "done").unwrap(), Resumed::Finished("done!".to_string()));
+    assert_eq!(co.status(&mut s), ThreadStatus::Dead);
+    assert_eq!(co.resume::<_, ()>(&mut s, ()).unwrap_err().kind(), ErrorKind::Runtime);
+
+    // errors and conversion failures
+    assert!(s.dostring("co = coroutine.create(function (x) coroutine.yield({}) error(x) end)"));
+    s.getglobal("co");
+    let co = s.to_thread(-1).unwrap();
+    s.pop(1);
+    let err = co.resume::<_, i32>(&mut s, "oops").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Conversion);
+    assert_eq!(co.status(&mut s), ThreadStatus::Suspended);
+    let err = co.resume::<_, ()>(&mut s, ()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Runtime);
+    assert!(err.message().ends_with("oops"));
+    assert!(s.dostring("assert(coroutine.status(co) == 'dead')"));
+
+    s.pushinteger(1);
+    assert!(s.to_thread(-1).is_none());
+}
+
+/// Doubles a number after a delay on another thread
+struct DoubleLater {
+    n: i64,
+    done: Option<Arc<AtomicBool>>
+}
+
+impl Future for DoubleLater {
+    type Output = Result<i64, String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<i64, String>> {
+        match self.done {
+            Some(ref done) if done.load(Ordering::SeqCst) => {
+                return Poll::Ready(if self.n < 0 { Err("negative".to_string()) } else { Ok(self.n * 2) });
+            }
+            Some(_) => return Poll::Pending,
+            None => ()
+        }
+        let done = Arc::new(AtomicBool::new(false));
+        self.done = Some(done.clone());
+        let waker = cx.waker().clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            done.store(true, Ordering::SeqCst);
+            waker.wake();
+        });
+        Poll::Pending
+    }
+}
+
+#[test]
+fn test_executor() {
+    let mut s = State::new();
+    s.openlibs();
+    s.register_async("double_later", |n: i64| DoubleLater{ n: n, done: None });
+    assert!(s.dostring("results = {}"));
+
+    let mut exec = Executor::new();
+    for n in 1..4 {
+        assert!(s.loadstring("local n = ... \
+                              local v = double_later(n) \
+                              coroutine.yield('ignored') \
+                              results[n] = v + double_later(v)").is_ok());
+        s.pushinteger(n);
+        exec.spawn(&mut s, 1);
+    }
+    assert_eq!(exec.len(), 3);
+    assert_eq!(s.gettop(), 0);
+    assert!(exec.run(&mut s).is_ok());
+    assert!(exec.is_empty());
+    assert!(s.dostring("assert(results[1] == 6 and results[2] == 12 and results[3] == 18)"));
+
+    // errors are raised at the position of the call
+    assert!(s.loadbuffer("double_later(-1)", "=task").is_ok());
+    exec.spawn(&mut s, 0);
+    let err = exec.run(&mut s).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Runtime);
+    assert_eq!(err.message(), "task:1: negative");
+
+    assert!(s.loadbuffer("double_later({})", "=task").is_ok());
+    exec.spawn(&mut s, 0);
+    let err = exec.run(&mut s).unwrap_err();
+    assert_eq!(err.message(), "task:1: bad argument (i64 expected, got table)");
+
+    // outside of a coroutine the call cannot yield
+    assert!(!s.dostring("double_later(1)"));
+}
+
+lua_extern! {
+    unsafe fn capture_traceback(L: &mut ExternState) -> i32 {
+        let frames = L.backtrace();
+        // level 0 is this function
+        L.pushstring(&Traceback(&frames[1..]).to_string());
+        1
+    }
+}
+
+#[test]
+fn test_backtrace() {
+    let mut s = State::new();
+    s.openlibs();
+    s.register("capture_traceback", capture_traceback);
+    assert!(s.dostring("function deep(n) \
+                            if n == 0 then return debug.traceback(), capture_traceback() end \
+                            local a, b = deep(n - 1) \
+                            return a, b \
+                        end"));
+    for &depth in &[0, 20, 21, 40] {
+        s.getglobal("deep");
+        s.pushinteger(depth);
+        s.call(1, 2);
+        let ours = s.read::<String>(-1).unwrap();
+        assert_eq!(s.read::<String>(-2), Ok(ours));
+        s.pop(2);
+    }
+
+    s.getglobal("deep");
+    s.pushinteger(40);
+    s.call(1, 2);
+    let tb = s.tostring(-1).unwrap().to_string();
+    assert!(tb.contains("\n\t...\n"));
+    s.pop(2);
+
+    assert_eq!(s.backtrace(), vec![]);
+    let frame = Frame{
+        source: "=stdin".to_string(),
+        short_src: "stdin".to_string(),
+        currentline: Some(3),
+        linedefined: 1,
+        name: None,
+        namewhat: String::new(),
+        what: "Lua".to_string()
+    };
+    assert_eq!(frame.to_string(), "stdin:3: in function <stdin:1>");
+}
+
+#[test]
+fn test_set_hook() {
+    let mut s = State::new();
+    s.openlibs();
+    assert!(s.dostring("function f(x) \
+                            return x + 1 \
+                        end"));
+
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let dropped = Rc::new(Cell::new(false));
+    {
+        let (lines, calls, flag) = (lines.clone(), calls.clone(), DropFlag(dropped.clone()));
+        s.set_hook(MASKCALL | MASKLINE, 0, move |L, ev| {
+            let _ = &flag;
+            match ev.event() {
+                DebugEvent::HookLine => lines.borrow_mut().push(ev.line().unwrap()),
+                DebugEvent::HookCall => calls.borrow_mut().push(ev.frame(L).name),
+                _ => ()
+            }
+        });
+    }
+    assert!(s.loadbuffer("local y = f(1)\nreturn y", "=chunk").is_ok());
+    s.call(0, 1);
+    assert_eq!(s.read::<i32>(-1), Ok(2));
+    s.pop(1);
+    assert_eq!(*lines.borrow(), vec![1, 1, 2]);
+    assert_eq!(calls.borrow()[1], Some("f".to_string()));
+
+    // the hook keeps running under a budget, and is restored afterwards
+    lines.borrow_mut().clear();
+    assert!(s.loadbuffer("while true do end", "=loop").is_ok());
+    let err = s.pcall_budget(0, 0, Budget::instructions(100)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::BudgetExceeded);
+    assert!(lines.borrow().len() > 10);
+    lines.borrow_mut().clear();
+    assert!(s.dostring("local a = 1"));
+    assert_eq!(*lines.borrow(), vec![1]);
+
+    // a hook can remove itself, which drops it
+    assert!(!dropped.get());
+    s.set_hook(MASKLINE, 0, |L, _| unsafe { L.remove_hook() });
+    assert!(dropped.get());
+    assert!(s.dostring("local a = 1\nlocal b = 2"));
+    assert_eq!(s.gethookmask(), 0);
+
+    // or replace itself, which drops it once it returns
+    let events = Rc::new(Cell::new(0));
+    dropped.set(false);
+    {
+        let (events, flag) = (events.clone(), DropFlag(dropped.clone()));
+        s.set_hook(MASKLINE, 0, move |L, _| {
+            let _ = &flag;
+            let events = events.clone();
+            unsafe { L.set_hook(MASKLINE, 0, move |_, _| events.set(events.get() + 1)) }
+        });
+    }
+    assert!(s.dostring("local a = 1\nlocal b = 2\nlocal c = 3"));
+    assert!(dropped.get());
+    assert_eq!(events.get(), 2);
+
+    // a hook that raises an error stays installed
+    s.set_hook(MASKLINE, 0, |L, _| unsafe { L.errorstr("hook error") });
+    for _ in 0..2 {
+        let err = s.dostring_("local a = 1").unwrap_err();
+        assert!(err.message().contains("hook error"));
+    }
+
+    // panics are raised as Lua errors
+    s.set_hook(MASKLINE, 0, |_, _| panic!("hook panic"));
+    let err = s.dostring_("local a = 1").unwrap_err();
+    assert!(err.message().contains("hook panic"));
+    s.remove_hook();
+}
+
+#[test]
+fn test_profiler() {
+    let mut s = State::new();
+    s.openlibs();
+    assert!(s.loadbuffer("local function fib(n)\n\
+                              if n < 2 then return n end\n\
+                              return fib(n - 1) + fib(n - 2)\n\
+                          end\n\
+                          local function fail() error('x') end\n\
+                          for i = 1, 3 do pcall(fail) end\n\
+                          return fib(15)", "=prof").is_ok());
+
+    let profiler = Profiler::new().sample_interval(100);
+    profiler.start(&mut s);
+    s.call(0, 1);
+    let profile = profiler.stop(&mut s);
+    assert_eq!(s.read::<i32>(-1), Ok(610));
+    assert_eq!(s.gethookmask(), 0);
+
+    let find = |label: &str| profile.functions().iter().find(|f| f.label() == label).unwrap();
+    let fib = find("fib (prof:1)");
+    assert_eq!(fib.calls, 1973);
+    assert!(fib.inclusive >= fib.exclusive);
+    // called by pcall, so it has no name
+    assert_eq!(find("? (prof:5)").calls, 3);
+    assert_eq!(find("pcall [C]").calls, 3);
+    // the errors leave no call open, so the main chunk comes first
+    assert_eq!(profile.functions()[0].label(), "main chunk (prof)");
+
+    assert!(!profile.samples().is_empty());
+    let mut folded = Vec::new();
+    profile.write_folded(&mut folded).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    // the main chunk tail-called fib, which hides it from the samples
+    assert!(folded.lines().any(|l| l.starts_with("(tail call);fib (prof:1);fib (prof:1)")));
+
+    let mut callgrind = Vec::new();
+    profile.write_callgrind(&mut callgrind).unwrap();
+    let callgrind = String::from_utf8(callgrind).unwrap();
+    assert!(callgrind.starts_with("# callgrind format\n"));
+    assert!(callgrind.contains("\nfn=fib (prof:1)\n"));
+    assert!(callgrind.contains("\ncfn=fib (prof:1)\ncalls=1972 1\n"));
+}
+
+#[test]
+fn test_coverage() {
+    let mut s = State::new();
+    s.openlibs();
+    let coverage = Coverage::new();
+    coverage.start(&mut s);
+    assert!(s.loadbuffer("local function sign(n)\n\
+                              if n < 0 then\n\
+                                  return -1\n\
+                              end\n\
+                              return 1\n\
+                          end\n\
+                          local function unused()\n\
+                              return 0\n\
+                          end\n\
+                          for i = 1, 3 do sign(i) end", "@scripts/sign.lua").is_ok());
+    s.call(0, 0);
+    // a chunk that never runs
+    assert!(s.loadbuffer("return function()\n\
+                              return 0\n\
+                          end", "@scripts/lazy.lua").is_ok());
+    coverage.add_function(&mut s);
+    assert_eq!(s.gettop(), 0);
+    let report = coverage.stop(&mut s);
+    assert_eq!(s.gethookmask(), 0);
+
+    let file = "scripts/sign.lua";
+    assert_eq!(report.hits(file, 2), Some(3));
+    assert_eq!(report.hits(file, 3), Some(0));
+    assert_eq!(report.hits(file, 5), Some(3));
+    // nested in a chunk that ran, but never called
+    assert_eq!(report.hits(file, 8), Some(0));
+    assert_eq!(report.hits(file, 11), None);
+    assert_eq!(report.hits("scripts/lazy.lua", 2), Some(0));
+    assert_eq!(report.hits("scripts/lazy.lua", 3), Some(0));
+
+    let mut lcov = Vec::new();
+    report.write_lcov(&mut lcov).unwrap();
+    let lcov = String::from_utf8(lcov).unwrap();
+    assert!(lcov.starts_with("TN:\nSF:scripts/lazy.lua\n"));
+    assert!(lcov.contains("end_of_record\nTN:\nSF:scripts/sign.lua\n"));
+    assert!(lcov.contains("\nDA:2,3\nDA:3,0\n"));
+    assert!(lcov.ends_with("end_of_record\n"));
+}
+
+struct ScriptedHandler {
+    log: Rc<RefCell<Vec<String>>>
+}
+
+impl DebugHandler for ScriptedHandler {
+    fn paused(&mut self, session: &mut Session, reason: PauseReason) -> Resume {
+        let line = session.frames()[0].currentline.unwrap_or(-1);
+        let locals: Vec<String> = session.locals(0).unwrap().iter()
+            .map(|v| match v.type_name {
+                "function" => format!("{}=<function>", v.name),
+                _ => format!("{}={}", v.name, v.value)
+            }).collect();
+        let mut log = self.log.borrow_mut();
+        log.push(format!("{:?} {} {}", reason, line, locals.join(",")));
+        match log.len() {
+            1 => {
+                assert_eq!(session.upvalues(0).unwrap()[0].name, "scale");
+                assert!(session.set_local(0, 3, 100));
+                assert!(!session.set_local(0, 10, 0));
+                Resume::StepOut
+            }
+            2 => Resume::StepIn,
+            3 => {
+                assert!(session.set_upvalue(0, 1, 2));
+                Resume::StepOver
+            }
+            _ => Resume::Continue
+        }
+    }
+}
+
+#[test]
+fn test_debugger() {
+    let mut s = State::new();
+    s.openlibs();
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let debugger = Debugger::new(ScriptedHandler{ log: log.clone() });
+    debugger.add_breakpoint("dbg", 4);
+    debugger.add_breakpoint("other", 4);
+    assert!(debugger.remove_breakpoint("other", 4));
+    assert_eq!(debugger.breakpoints(), vec![("dbg".to_string(), 4)]);
+    debugger.attach(&mut s);
+
+    assert!(s.loadbuffer("local scale = 1\n\
+                          local function add(a, b)\n\
+                              local sum = (a + b) * scale\n\
+                              return sum\n\
+                          end\n\
+                          local x = add(1, 2)\n\
+                          local y = add(x, 10)\n\
+                          result = y\n\
+                          error('boom')", "=dbg").is_ok());
+    let err = debugger.pcall(&mut s, 0, 0).unwrap_err();
+    assert_eq!(err.message(), "dbg:9: boom");
+    debugger.detach(&mut s);
+    assert_eq!(s.gettop(), 0);
+
+    s.getglobal("result");
+    assert_eq!(s.read::<i32>(-1), Ok(220));
+    assert_eq!(*log.borrow(), vec![
+        "Breakpoint(\"dbg\", 4) 4 a=1,b=2,sum=3".to_string(),
+        "Step 7 scale=1,add=<function>,x=100".to_string(),
+        "Step 3 a=100,b=10".to_string(),
+        "Step 4 a=100,b=10,sum=220".to_string(),
+        "Error(\"dbg:9: boom\") -1 ".to_string()
+    ]);
+}
+
+#[test]
+fn test_dap() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let server = DapServer::new(stream.try_clone().unwrap(), stream).root("/project");
+
+    let client = thread::spawn(move || {
+        let mut reader = io::BufReader::new(client.try_clone().unwrap());
+        let mut malformed = client.try_clone().unwrap();
+        let mut writer = client;
+        let mut seq = 0;
+        let mut request = |command: &str, args: &str| {
+            seq += 1;
+            let msg = format!("{{\"seq\":{},\"type\":\"request\",\"command\":\"{}\",\"arguments\":{}}}",
+                              seq, command, args);
+            write_message(&mut writer, &Json::parse(&msg).unwrap()).unwrap();
+        };
+        let mut next = || read_message(&mut reader).unwrap().unwrap();
+        let mut log = Vec::new();
+
+        // a malformed message gets an error response
+        malformed.write_all(b"Content-Length: 5\r\n\r\n{oops").unwrap();
+        log.push(next().to_string());
+
+        request("initialize", "{\"adapterID\":\"lua\"}");
+        let msg = next();
+        assert_eq!(msg.get("success"), Some(&Json::Bool(true)));
+        assert!(msg.get("body").unwrap().get("supportsConfigurationDoneRequest").is_some());
+        request("setBreakpoints", "{\"source\":{\"path\":\"/project/dap.lua\"},\"breakpoints\":[{\"line\":3}]}");
+        request("configurationDone", "{}");
+        log.push(next().to_string());
+        log.push(next().to_string());
+        assert_eq!(next().get("command").unwrap().as_str(), Some("configurationDone"));
+
+        // paused at the breakpoint
+        assert_eq!(next().get("body").unwrap().get("reason").unwrap().as_str(), Some("breakpoint"));
+        request("stackTrace", "{\"threadId\":1}");
+        let frames = next().get("body").unwrap().get("stackFrames").unwrap().clone();
+        log.push(frames.as_array().unwrap()[0].to_string());
+        request("scopes", "{\"frameId\":0}");
+        let scopes = next().get("body").unwrap().get("scopes").unwrap().clone();
+        assert_eq!(scopes.as_array().unwrap()[0].get("variablesReference").unwrap().as_i64(), Some(1));
+        request("setVariable", "{\"variablesReference\":1,\"name\":\"sum\",\"value\":\"7\"}");
+        log.push(next().get("body").unwrap().to_string());
+        request("variables", "{\"variablesReference\":1}");
+        log.push(next().get("body").unwrap().to_string());
+        request("next", "{\"threadId\":1}");
+        assert_eq!(next().get("success"), Some(&Json::Bool(true)));
+
+        // paused after the step
+        assert_eq!(next().get("body").unwrap().get("reason").unwrap().as_str(), Some("step"));
+        request("variables", "{\"variablesReference\":3}");
+        let globals = next().get("body").unwrap().get("variables").unwrap().clone();
+        log.push(globals.as_array().unwrap().iter()
+                 .find(|v| v.get("name").unwrap().as_str() == Some("total")).unwrap().to_string());
+        request("continue", "{\"threadId\":1}");
+        assert_eq!(next().get("success"), Some(&Json::Bool(true)));
+
+        // paused at the error
+        log.push(next().get("body").unwrap().to_string());
+        request("continue", "{\"threadId\":1}");
+        assert_eq!(next().get("success"), Some(&Json::Bool(true)));
+        log.push(next().to_string());
+        log
+    });
+
+    let mut s = State::new();
+    s.openlibs();
+    server.attach(&mut s);
+    assert_eq!(server.debugger().breakpoints(), vec![
+        ("/project/dap.lua".to_string(), 3),
+        ("dap.lua".to_string(), 3)
+    ]);
+    assert!(s.loadbuffer("local function add(a, b)\n\
+                          local sum = a + b\n\
+                          return sum\n\
+                          end\n\
+                          total = add(1, 2)\n\
+                          error('done')", "@dap.lua").is_ok());
+    let err = server.pcall(&mut s, 0, 0).unwrap_err();
+    assert_eq!(err.message(), "dap.lua:6: done");
+    server.detach(&mut s);
+    s.getglobal("total");
+    assert_eq!(s.read::<i32>(-1), Ok(7));
+
+    assert_eq!(client.join().unwrap(), vec![
+        "{\"seq\":1,\"type\":\"response\",\"request_seq\":0,\"success\":false,\"command\":\"\",\
+         \"message\":\"invalid JSON message\"}",
+        "{\"seq\":3,\"type\":\"event\",\"event\":\"initialized\",\"body\":{}}",
+        "{\"seq\":4,\"type\":\"response\",\"request_seq\":2,\"success\":true,\"command\":\"setBreakpoints\",\
+         \"body\":{\"breakpoints\":[{\"verified\":true,\"line\":3}]}}",
+        "{\"id\":0,\"name\":\"add\",\"line\":3,\"column\":1,\
+         \"source\":{\"name\":\"dap.lua\",\"path\":\"/project/dap.lua\"}}",
+        "{\"value\":\"7\",\"type\":\"number\"}",
+        "{\"variables\":[{\"name\":\"a\",\"value\":\"1\",\"type\":\"number\",\"variablesReference\":0},\
+         {\"name\":\"b\",\"value\":\"2\",\"type\":\"number\",\"variablesReference\":0},\
+         {\"name\":\"sum\",\"value\":\"7\",\"type\":\"number\",\"variablesReference\":0}]}",
+        "{\"name\":\"total\",\"value\":\"7\",\"type\":\"number\",\"variablesReference\":0}",
+        "{\"reason\":\"exception\",\"threadId\":1,\"allThreadsStopped\":true,\"text\":\"dap.lua:6: done\"}",
+        "{\"seq\":17,\"type\":\"event\",\"event\":\"terminated\",\"body\":{}}"
+    ]);
+
+    // the length of a message and of its header lines is capped
+    let mut input = io::Cursor::new(b"Content-Length: 100000000000\r\n\r\n{}".to_vec());
+    assert_eq!(read_body(&mut input).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    let mut input = io::Cursor::new(vec![b'x'; 1 << 20]);
+    assert_eq!(read_body(&mut input).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    // so is the nesting of JSON values
+    assert!(Json::parse(&format!("{}{}", "[".repeat(128), "]".repeat(128))).is_some());
+    assert!(Json::parse(&"[".repeat(1 << 20)).is_none());
+}
+
+/// Reads a message framed by a Content-Length header, like a DAP client.
+fn read_message<R: io::BufRead>(r: &mut R) -> io::Result<Option<Json>> {
+    match read_body(r)? {
+        Some(body) => match Json::parse(&String::from_utf8_lossy(&body)) {
+            Some(msg) => Ok(Some(msg)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid JSON message"))
+        },
+        None => Ok(None)
+    }
+}
+
+#[test]
+fn test_repl() {
+    let mut s = State::new();
+    s.openlibs();
+    s.pushinteger(7);
+    let input = "x = 1 + 1\n\
+                 =x, 'two'\n\
+                 function f(a)\n\
+                   return a * 10\n\
+                 end\n\
+                 return f(x), setmetatable({}, {__tostring = function() return 'obj' end})\n\
+                 error('bad')\n\
+                 x = = 1\n\
+                 for i = 1, 2 do";
+    let mut output = Vec::new();
+    let mut prompts = Vec::new();
+    let history = {
+        let mut repl = Repl::new(LineInput::new(io::Cursor::new(input), &mut prompts), &mut output)
+            .prompts("lua> ", "...> ");
+        repl.run(&mut s).unwrap();
+        repl.history().to_vec()
+    };
+    assert_eq!(s.gettop(), 1);
+    assert_eq!(history.len(), 7);
+    assert_eq!(history[2], "function f(a)\nreturn a * 10\nend");
+    assert_eq!(String::from_utf8(prompts).unwrap(),
+               "lua> lua> lua> ...> ...> lua> lua> lua> lua> ...> lua> ");
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(&lines[..3], &["2\ttwo", "20\tobj", "stdin:1: bad"]);
+    assert_eq!(lines[3], "stack traceback:");
+    assert!(lines.contains(&"stdin:1: unexpected symbol near '='"), "{}", output);
+    assert_eq!(lines.last(), Some(&"stdin:1: 'end' expected near '<eof>'"));
+}
@@ -0,0 +1,191 @@
+//! Coroutines with typed resume and yield
+
+use libc::c_int;
+
+use {State, ExternState, RawState, LuaRef, ToLua, FromLua, PCallError, Error, ErrorKind};
+use raw;
+use error::pop_error;
+
+/// A Lua coroutine, kept alive by a registry reference.
+///
+/// The coroutine belongs to the state that created it, and must only be used
+/// with that state (or another thread of it).
+#[derive(Debug)]
+pub struct Thread {
+    L: *mut raw::lua_State,
+    anchor: LuaRef
+}
+
+/// The outcome of a successful Thread::resume()
+#[derive(Clone,PartialEq,Debug)]
+pub enum Resumed<T> {
+    /// The coroutine yielded these values, and can be resumed again
+    Yielded(T),
+    /// The coroutine returned these values, and is now dead
+    Finished(T)
+}
+
+/// The status of a Thread
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum ThreadStatus {
+    /// The coroutine has not started yet, or has yielded
+    Suspended,
+    /// The coroutine is the one running the caller
+    Running,
+    /// The coroutine finished, or stopped with an error
+    Dead
+}
+
+impl Thread {
+    /// Resumes the coroutine with the arguments `args`, which are passed to
+    /// the main function on the first resume, and returned from yield
+    /// otherwise. The values yielded or returned by the coroutine are
+    /// converted to `R` (a tuple reads one value each) and removed from its
+    /// stack.
+    ///
+    /// An error in the coroutine is returned as an Error, and leaves the
+    /// coroutine dead. A conversion failure is returned as an Error of kind
+    /// Conversion.
+    ///
+    /// Fails the task if the thread belongs to a different state.
+    pub fn resume<A: ToLua, R: FromLua>(&self, L: &mut State, args: A)
+                                       -> Result<Resumed<R>,Error> {
+        #![inline(always)]
+        unsafe { L.as_extern().resume_thread(self, args) }
+    }
+
+    /// Returns the status of the coroutine.
+    ///
+    /// Fails the task if the thread belongs to a different state.
+    pub fn status(&self, L: &mut State) -> ThreadStatus {
+        #![inline(always)]
+        unsafe { L.as_extern().thread_status(self) }
+    }
+
+    /// Returns the registry reference that keeps the coroutine alive. Use
+    /// pushref() to push the coroutine onto the stack.
+    pub fn anchor(&self) -> &LuaRef {
+        &self.anchor
+    }
+}
+
+impl State {
+    /// Pops the function on top of the stack and returns a new coroutine
+    /// whose main function it is.
+    pub fn new_thread(&mut self) -> Thread {
+        #![inline(always)]
+        unsafe { self.as_extern().new_thread() }
+    }
+
+    /// Returns the coroutine at the given acceptable index, e.g. one created
+    /// by coroutine.create(), or None if the value is not a thread.
+    pub fn to_thread(&mut self, idx: i32) -> Option<Thread> {
+        #![inline(always)]
+        unsafe { self.as_extern().to_thread(idx) }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn new_thread(&mut self) -> Thread {
+        luaassert!(self, self.isfunction(-1), "new_thread: function expected");
+        self.checkstack_(2);
+        self.as_raw().new_thread()
+    }
+
+    pub unsafe fn to_thread(&mut self, idx: i32) -> Option<Thread> {
+        self.check_acceptable(idx);
+        self.checkstack_(2);
+        self.as_raw().to_thread(idx)
+    }
+
+    pub unsafe fn resume_thread<A: ToLua, R: FromLua>(&mut self, th: &Thread, args: A)
+                                                      -> Result<Resumed<R>,Error> {
+        self.check_thread(th);
+        let mut co = RawState::from_lua_State(th.L);
+        luaassert!(self, co.checkstack(1), "resume_thread: cannot grow stack");
+        self.as_raw().resume_thread(th, args)
+    }
+
+    pub unsafe fn thread_status(&mut self, th: &Thread) -> ThreadStatus {
+        self.check_thread(th);
+        self.as_raw().thread_status(th)
+    }
+
+    /// Yields the coroutine running this CFunction with the values `vals`.
+    /// Must be used as the return expression of the CFunction:
+    ///
+    ///   return L.yield_values((1, "two"));
+    pub unsafe fn yield_values<T: ToLua>(&mut self, vals: T) -> c_int {
+        let base = self.gettop();
+        self.push(vals);
+        let n = self.gettop() - base;
+        self.as_raw().yield_(n)
+    }
+
+    unsafe fn check_thread(&mut self, th: &Thread) {
+        // pushref() fails on a reference from a different state
+        self.pushref(&th.anchor);
+        self.pop(1);
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn new_thread(&mut self) -> Thread {
+        let co = raw::lua_newthread(self.L);
+        // move the function into the coroutine, below the anchored thread
+        self.insert(-2);
+        raw::lua_xmove(self.L, co, 1);
+        Thread{ L: co, anchor: self.newref() }
+    }
+
+    pub unsafe fn to_thread(&mut self, idx: i32) -> Option<Thread> {
+        let co = raw::lua_tothread(self.L, idx as c_int);
+        if co.is_null() {
+            return None;
+        }
+        self.pushvalue(idx);
+        Some(Thread{ L: co, anchor: self.newref() })
+    }
+
+    pub unsafe fn resume_thread<A: ToLua, R: FromLua>(&mut self, th: &Thread, args: A)
+                                                      -> Result<Resumed<R>,Error> {
+        match self.thread_status(th) {
+            ThreadStatus::Suspended => (),
+            ThreadStatus::Running => {
+                return Err(Error::new(ErrorKind::Runtime, "cannot resume running coroutine"))
+            }
+            ThreadStatus::Dead => {
+                return Err(Error::new(ErrorKind::Runtime, "cannot resume dead coroutine"))
+            }
+        }
+        let mut co = RawState::from_lua_State(th.L);
+        let nargs = co.push(args);
+        let status = raw::lua_resume(th.L, nargs as c_int);
+        match status {
+            0 | raw::LUA_YIELD => {
+                let nres = co.gettop();
+                let vals = R::from_lua(&mut co, 1);
+                co.pop(nres);
+                let vals = vals?;
+                Ok(if status == 0 { Resumed::Finished(vals) } else { Resumed::Yielded(vals) })
+            }
+            code => {
+                let kind = PCallError::from_code(code).map_or(ErrorKind::Runtime, ErrorKind::from);
+                Err(pop_error(&mut co, kind))
+            }
+        }
+    }
+
+    pub unsafe fn thread_status(&mut self, th: &Thread) -> ThreadStatus {
+        if th.L == self.L {
+            return ThreadStatus::Running;
+        }
+        match raw::lua_status(th.L) {
+            raw::LUA_YIELD => ThreadStatus::Suspended,
+            0 if raw::lua_gettop(th.L) > 0 => ThreadStatus::Suspended,
+            _ => ThreadStatus::Dead
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! Rust values as Lua userdata
+
+use libc;
+use libc::c_int;
+use std::any::{Any, TypeId};
+use std::{mem, ptr};
+
+use {State, ExternState, RawState, CFunction, REGISTRYINDEX};
+use raw;
+use extra::extra;
+use macros::extern_call;
+use closure::{pcall_upvalue, drop_in_lua};
+
+/// Rust types that can be moved into Lua as full userdata.
+///
+/// Every type gets its own metatable, identified by its `TypeId`, so two types
+/// with the same `NAME` never collide. The value is dropped by the `__gc`
+/// metamethod when the userdata is collected (or when the state is closed).
+pub trait UserData: Any + Sized {
+    /// The type name used in error messages, e.g. "Vec3"
+    const NAME: &'static str;
+
+    /// Adds the methods and metamethods of the type. The default adds none.
+    fn add_methods(_methods: &mut UserDataMethods<Self>) {}
+}
+
+/// A method of a userdata type `T`.
+///
+/// It is called like a CFunction, with the userdata as argument 1, and
+/// returns the number of results it pushed.
+///
+/// The value is borrowed while the method runs, like a `RefCell`: using it as
+/// a `T` again before the method returns, e.g. with check_userdata() or by
+/// calling one of its methods from Lua, raises an error. The method runs in
+/// protected mode so the value can be released even if it raises an error,
+/// which means it cannot yield.
+pub type Method<T> = unsafe fn(&mut ExternState, &mut T) -> i32;
+
+enum Entry<T> {
+    Method(Method<T>),
+    Function(CFunction)
+}
+
+/// The methods and metamethods of a userdata type, collected by
+/// UserData::add_methods().
+pub struct UserDataMethods<T> {
+    methods: Vec<(String, Entry<T>)>,
+    meta: Vec<(String, Entry<T>)>
+}
+
+impl<T: UserData> UserDataMethods<T> {
+    /// Adds a method, callable from Lua as `ud:name(...)`.
+    pub fn add_method(&mut self, name: &str, f: Method<T>) {
+        self.methods.push((name.to_string(), Entry::Method(f)));
+    }
+
+    /// Adds a plain CFunction to the method table, e.g. a constructor that
+    /// does not take the userdata as its first argument.
+    pub fn add_function(&mut self, name: &str, f: CFunction) {
+        self.methods.push((name.to_string(), Entry::Function(f)));
+    }
+
+    /// Adds a metamethod, e.g. "__tostring" or "__add". The userdata is
+    /// checked to be argument 1; use add_meta_function() for metamethods
+    /// where it may be the second operand.
+    ///
+    /// An "__index" metamethod is only called for the keys that are not
+    /// methods added with add_method().
+    ///
+    /// Fails the task if `name` is "__gc", which is reserved for dropping the
+    /// value.
+    pub fn add_meta_method(&mut self, name: &str, f: Method<T>) {
+        self.add_meta(name, Entry::Method(f))
+    }
+
+    /// Adds a plain CFunction as a metamethod. See add_meta_method().
+    pub fn add_meta_function(&mut self, name: &str, f: CFunction) {
+        self.add_meta(name, Entry::Function(f))
+    }
+
+    fn add_meta(&mut self, name: &str, entry: Entry<T>) {
+        assert!(name != "__gc", "add_meta_method: __gc is reserved for dropping {}", T::NAME);
+        self.meta.push((name.to_string(), entry));
+    }
+}
+
+/// The Lua-owned storage of a userdata value
+struct Cell<T> {
+    alive: bool,
+    borrowed: bool,
+    value: T
+}
+
+// Lua aligns userdata memory for doubles and pointers
+const LUA_ALIGN: usize = 8;
+
+/// Returns the size of the userdata block needed for a Cell<T>, including
+/// padding for types with stricter alignment than Lua provides.
+fn block_size<T>() -> usize {
+    let align = mem::align_of::<Cell<T>>();
+    mem::size_of::<Cell<T>>() + if align > LUA_ALIGN { align - 1 } else { 0 }
+}
+
+/// Returns the Cell<T> within the userdata block at `p`.
+fn cell_ptr<T>(p: *mut libc::c_void) -> *mut Cell<T> {
+    let align = mem::align_of::<Cell<T>>();
+    let addr = p as usize;
+    ((addr + align - 1) & !(align - 1)) as *mut Cell<T>
+}
+
+impl State {
+    /// Moves `val` into a new full userdata and pushes it onto the stack. The
+    /// userdata gets the metatable of `T`, created on first use from
+    /// UserData::add_methods().
+    pub fn push_userdata<T: UserData>(&mut self, val: T) {
+        #![inline(always)]
+        unsafe { self.as_extern().push_userdata(val) }
+    }
+
+    /// Checks whether the function argument `narg` is a userdata of type `T`
+    /// and returns a reference to its value. Raises a type error otherwise,
+    /// or an error if the value was already finalized or is borrowed by a
+    /// running method.
+    ///
+    /// The reference must not outlive the userdata, nor be held while
+    /// another reference to the same value is obtained.
+    pub fn check_userdata<T: UserData>(&mut self, narg: i32) -> &mut T {
+        #![inline(always)]
+        unsafe { self.as_extern().check_userdata(narg) }
+    }
+
+    /// Returns a reference to the value of the userdata at the given
+    /// acceptable index if it is a live userdata of type `T` that is not
+    /// borrowed by a running method, or None. See check_userdata().
+    pub fn to_userdata<T: UserData>(&mut self, idx: i32) -> Option<&mut T> {
+        #![inline(always)]
+        unsafe { self.as_extern().to_userdata(idx) }
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> ExternState<'l> {
+    pub unsafe fn push_userdata<T: UserData>(&mut self, val: T) {
+        // the userdata, and up to 4 slots for creating the metatable
+        self.checkstack_(5);
+        self.as_raw().push_userdata(val)
+    }
+
+    /// Note: the reference is returned as 'static to prevent borrowing the
+    /// ExternState, but its lifetime is actually that of the userdata.
+    pub unsafe fn check_userdata<T: UserData>(&mut self, narg: i32) -> &'static mut T {
+        self.check_acceptable(narg);
+        self.checkstack_(3);
+        self.as_raw().check_userdata(narg)
+    }
+
+    /// Note: the reference is returned as 'static to prevent borrowing the
+    /// ExternState, but its lifetime is actually that of the userdata.
+    pub unsafe fn to_userdata<T: UserData>(&mut self, idx: i32) -> Option<&'static mut T> {
+        self.check_acceptable(idx);
+        self.checkstack_(3);
+        self.as_raw().to_userdata(idx)
+    }
+}
+
+#[allow(missing_docs)]
+impl<'l> RawState<'l> {
+    pub unsafe fn push_userdata<T: UserData>(&mut self, val: T) {
+        let p = self.newuserdata(block_size::<T>());
+        ptr::write(cell_ptr::<T>(p), Cell{ alive: true, borrowed: false, value: val });
+        self.push_userdata_metatable::<T>();
+        self.setmetatable(-2);
+    }
+
+    pub unsafe fn check_userdata<T: UserData>(&mut self, narg: i32) -> &'static mut T {
+        &mut (*self.check_cell::<T>(narg)).value
+    }
+
+    pub unsafe fn to_userdata<T: UserData>(&mut self, idx: i32) -> Option<&'static mut T> {
+        match self.userdata_cell::<T>(idx) {
+            Some(cell) if (*cell).alive && !(*cell).borrowed => Some(&mut (*cell).value),
+            _ => None
+        }
+    }
+
+    /// Returns the Cell<T> of argument `narg`, raising an error if it is not
+    /// a userdata of type `T`, or if its value is finalized or borrowed.
+    unsafe fn check_cell<T: UserData>(&mut self, narg: i32) -> *mut Cell<T> {
+        match self.userdata_cell::<T>(narg) {
+            Some(cell) if !(*cell).alive => {
+                self.argerror(narg, &format!("attempt to use a finalized {}", T::NAME))
+            }
+            Some(cell) if (*cell).borrowed => {
+                self.argerror(narg, &format!("attempt to use a {} that is already in use", T::NAME))
+            }
+            Some(cell) => cell,
+            None => self.typerror(narg, T::NAME)
+        }
+    }
+
+    /// Returns the Cell<T> of the value at `idx` if it is a userdata with the
+    /// metatable of `T`.
+    unsafe fn userdata_cell<T: UserData>(&mut self, idx: i32) -> Option<*mut Cell<T>> {
+        let p = self.touserdata(idx);
+        if p.is_null() || !self.getmetatable(idx) {
+            return None;
+        }
+        let r = match (*extra(self)).metatables.get(&TypeId::of::<T>()) {
+            Some(&r) => r,
+            None => {
+                self.pop(1);
+                return None;
+            }
+        };
+        self.rawgeti(REGISTRYINDEX, r);
+        let same = self.rawequal(-1, -2);
+        self.pop(2);
+        if same { Some(cell_ptr::<T>(p)) } else { None }
+    }
+
+    /// Pushes the metatable of `T`, creating it on first use.
+    unsafe fn push_userdata_metatable<T: UserData>(&mut self) {
+        let id = TypeId::of::<T>();
+        if let Some(&r) = (*extra(self)).metatables.get(&id) {
+            self.rawgeti(REGISTRYINDEX, r);
+            return;
+        }
+        let mut methods = UserDataMethods{ methods: Vec::new(), meta: Vec::new() };
+        T::add_methods(&mut methods);
+
+        self.createtable(0, methods.meta.len() as i32 + 2);
+        for (name, entry) in methods.meta {
+            self.push_entry(entry);
+            self.setfield(-2, &name);
+        }
+        if !methods.methods.is_empty() {
+            self.createtable(0, methods.methods.len() as i32);
+            for (name, entry) in methods.methods {
+                self.push_entry(entry);
+                self.setfield(-2, &name);
+            }
+            // look up the methods before calling a user __index
+            self.getfield(-2, "__index");
+            if self.isnil(-1) {
+                self.pop(1);
+            } else {
+                self.pushcclosure(index_trampoline, 2);
+            }
+            self.setfield(-2, "__index");
+        }
+        self.pushcfunction(userdata_gc::<T>);
+        self.setfield(-2, "__gc");
+
+        self.pushvalue(-1);
+        let r = self.ref_(REGISTRYINDEX);
+        (*extra(self)).metatables.insert(id, r);
+    }
+
+    unsafe fn push_entry<T: UserData>(&mut self, entry: Entry<T>) {
+        match entry {
+            Entry::Method(f) => {
+                self.pushlightuserdata(f as *mut libc::c_void);
+                self.pushcclosure(method_call::<T>, 1);
+                self.push_error_handler();
+                self.pushcclosure(method_trampoline::<T>, 2);
+            }
+            Entry::Function(f) => self.pushcfunction(f)
+        }
+    }
+}
+
+/// Borrows the userdata at argument 1, and calls the method in upvalue 1 in
+/// protected mode, with the message handler in upvalue 2.
+unsafe extern "C" fn method_trampoline<T: UserData>(L: *mut raw::lua_State) -> c_int {
+    let mut state = RawState::from_lua_State(L);
+    let cell = state.check_cell::<T>(1);
+    // keep the userdata below the call so it outlives the borrow
+    let nargs = state.gettop();
+    state.checkstack_(1);
+    state.pushvalue(1);
+    state.insert(1);
+    (*cell).borrowed = true;
+    let status = pcall_upvalue(L, 1, nargs);
+    (*cell).borrowed = false;
+    if status != 0 {
+        raw::lua_error(L)
+    } else {
+        raw::lua_gettop(L) - 1
+    }
+}
+
+/// Calls the method in upvalue 1 on the userdata at argument 1, which was
+/// checked and borrowed by method_trampoline().
+unsafe extern "C" fn method_call<T: UserData>(L: *mut raw::lua_State) -> c_int {
+    let p = raw::lua_touserdata(L, raw::lua_upvalueindex(1));
+    let f = mem::transmute::<*mut libc::c_void, Method<T>>(p);
+    let cell = cell_ptr::<T>(raw::lua_touserdata(L, 1));
+    extern_call(L, || {
+        let mut state = ExternState::from_lua_State(L);
+        f(&mut state, &mut (*cell).value)
+    })
+}
+
+/// Looks up key 2 in the methods table in upvalue 1, and calls the __index
+/// metamethod in upvalue 2 if it is not a method.
+unsafe extern "C" fn index_trampoline(L: *mut raw::lua_State) -> c_int {
+    let mut state = RawState::from_lua_State(L);
+    state.settop(2);
+    state.pushvalue(2);
+    state.rawget(raw::lua_upvalueindex(1));
+    if !state.isnil(-1) {
+        return 1;
+    }
+    state.pop(1);
+    state.pushvalue(raw::lua_upvalueindex(2));
+    state.insert(1);
+    state.call(2, 1);
+    1
+}
+
+unsafe extern "C" fn userdata_gc<T: UserData>(L: *mut raw::lua_State) -> c_int {
+    let cell = cell_ptr::<T>(raw::lua_touserdata(L, 1));
+    if (*cell).borrowed {
+        // only a script calling __gc itself can get here
+        RawState::from_lua_State(L).errorstr("attempt to finalize a userdata that is in use")
+    }
+    if (*cell).alive {
+        (*cell).alive = false;
+        drop_in_lua(L, &mut (*cell).value)
+    } else {
+        0
+    }
+}